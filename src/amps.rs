@@ -1,40 +1,175 @@
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::ErrorKind;
+use std::io;
 use std::io::Read;
+use std::path::{Component, Path, PathBuf};
 
 use crate::engine::scan;
 use crate::engine::parser;
+use crate::engine::context::{RegexCache, Settings, Truthiness};
+use crate::engine::error::{AmpsError, ErrorKind};
+use crate::engine::escape::Escape;
+use crate::engine::value::Value;
 
 pub struct Amps {
     template: Option<String>,
-    errors: Vec<String>,
+    template_name: Option<String>,
+    template_root: Option<PathBuf>,
+    errors: Vec<AmpsError>,
+    settings: Settings,
+    autoescape_set: bool,
+    variables: HashMap<String, Value>,
+    regexes: RegexCache,
+}
+
+impl Default for Amps {
+    fn default() -> Amps {
+        Amps::new()
+    }
 }
 
 impl Amps {
     pub fn new() -> Amps {
         Amps {
             template: None,
+            template_name: None,
+            template_root: None,
             errors: Vec::new(),
+            settings: Settings::default(),
+            autoescape_set: false,
+            variables: HashMap::new(),
+            regexes: RegexCache::default(),
         }
     }
 
-    pub fn render(&mut self) {
+    // how many times a while loop can run before it's stopped with an error
+    pub fn set_max_iterations(&mut self, max: usize) {
+        self.settings.max_iterations = max;
+    }
+
+    // how many macro calls can be nested before the call is stopped with an
+    // error. Every call is also an expression level, so calls nested deeper
+    // than expressions can be are stopped anyway.
+    pub fn set_max_macro_depth(&mut self, max: usize) {
+        self.settings.max_macro_depth = max;
+    }
+
+    // templates loaded from a file, extended or imported are looked up
+    // inside root and cannot name a file outside it. Without a root they're
+    // paths relative to the working directory.
+    pub fn set_template_root(&mut self, root: impl Into<PathBuf>) {
+        self.template_root = Some(root.into());
+    }
+
+    // set
+    //
+    // Makes value available to templates as name, replacing the value name
+    // had. Variables are kept across renders until they're unset or cleared.
+    pub fn set(&mut self, name: &str, value: impl Into<Value>) {
+        self.variables.insert(name.to_string(), value.into());
+    }
+
+    // removes the variable name, returning its value
+    pub fn unset(&mut self, name: &str) -> Option<Value> {
+        self.variables.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.variables.get(name)
+    }
+
+    // sets every (name, value) pair in variables
+    pub fn extend<I, K, V>(&mut self, variables: I)
+    where I: IntoIterator<Item = (K, V)>,
+          K: Into<String>,
+          V: Into<Value> {
+        for (name, value) in variables {
+            self.variables.insert(name.into(), value.into());
+        }
+    }
+
+    // removes every variable
+    pub fn clear(&mut self) {
+        self.variables.clear();
+    }
+
+    // escapes every echoed value for the format of the output. Unless it's
+    // set, templates loaded from a file are escaped for their extension
+    pub fn set_autoescape(&mut self, escape: Escape) {
+        self.settings.escape = escape;
+        self.autoescape_set = true;
+    }
+
+    // which values conditions accept, only booleans by default
+    pub fn set_truthiness(&mut self, truthiness: Truthiness) {
+        self.settings.truthiness = truthiness;
+    }
+
+    // lets operators mix texts with other values: a text holding a number is
+    // taken as that number and + joins a text with anything else as text,
+    // like "n=" + 5
+    pub fn set_coercion(&mut self, coercion: bool) {
+        self.settings.coercion = coercion;
+    }
+
+    // adds a test templates can use as `x is name`, it replaces a builtin
+    // test with the same name
+    pub fn add_test<F>(&mut self, name: &str, test: F)
+    where F: Fn(&Value) -> bool + Send + Sync + 'static {
+        self.settings.tests.insert(name.to_string(), Box::new(test));
+    }
+
+    // render
+    //
+    // Renders the template to stdout, see render_to
+    pub fn render(&self) -> Result<(), Vec<AmpsError>> {
+        self.render_to(&mut io::stdout())
+    }
+
+    // render_to
+    //
+    // Writes the rendered template to out, byte by byte as the template
+    // produced it. The output is written even when rendering finds errors,
+    // they're returned afterwards.
+    pub fn render_to(&self, out: &mut impl io::Write) -> Result<(), Vec<AmpsError>> {
         let metainfo = match &self.template {
-            Some(tpl) => scan::scanner::scan(&tpl),
-            None      => {
-                self.errors.push(String::from("no template available"));
-                return;
-            },
+            Some(tpl) => scan::scanner::scan(tpl, self.template_name.as_deref()),
+            None      => return Err(vec![AmpsError::new(ErrorKind::Template,
+                                                               String::from("no template available"))]),
         };
 
         //for info in metainfo {
         //    println!("{:#?}", info);
         //}
 
-        parser::parsing::parse(&metainfo);
+        let loader = |name: &str| self.read_template(name);
+        let (output, mut errors) = parser::parsing::parse(&metainfo, &loader, &self.settings,
+                                                          &self.variables, &self.regexes);
+
+        if let Err(e) = out.write_all(output.as_bytes()).and_then(|_| out.flush()) {
+            errors.push(AmpsError::new(ErrorKind::Io, format!("cannot write the rendered template: {}", e)));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        }
+        else {
+            Err(errors)
+        }
+    }
+
+    // render_to_string
+    //
+    // Returns the rendered template, or the errors found rendering it
+    pub fn render_to_string(&self) -> Result<String, Vec<AmpsError>> {
+        let mut out: Vec<u8> = Vec::new();
+        self.render_to(&mut out)?;
+
+        // everything rendered comes from Strings, so it's valid UTF-8
+        Ok(String::from_utf8_lossy(&out).into_owned())
     }
 
-    pub fn get_errors(&self) -> &Vec<String> {
+    pub fn get_errors(&self) -> &Vec<AmpsError> {
         &self.errors
     }
 
@@ -42,43 +177,198 @@ impl Amps {
         &self.template
     }
 
+    // a template given as text isn't escaped unless set_autoescape says so
     pub fn load_template(&mut self, template: String) {
         self.template = Some(template);
+        self.template_name = None;
+        self.regexes.get_mut().unwrap_or_else(|e| e.into_inner()).clear();
+        if !self.autoescape_set {
+            self.settings.escape = Escape::Off;
+        }
+    }
+
+    // the template keeps the escape of the previous one when it can't be read
+    pub fn load_template_from_file(&mut self, filename: &str) {
+        match self.read_template(filename) {
+            Ok(result) => {
+                self.template = Some(result);
+                self.template_name = Some(filename.trim().to_string());
+                self.regexes.get_mut().unwrap_or_else(|e| e.into_inner()).clear();
+                if !self.autoescape_set {
+                    self.settings.escape = Escape::from_extension(filename);
+                }
+            },
+            Err(errmsg) => self.errors.push(AmpsError::new(ErrorKind::Template, errmsg)),
+        }
     }
 
-    pub fn load_template_from_file(&mut self, filename: &String) {
-        let file = File::open(&filename.trim());
+    // read_template
+    //
+    // Returns the content of a template file, it's also how templates find the
+    // ones they extend or import
+    fn read_template(&self, filename: &str) -> Result<String, String> {
+        let path = match &self.template_root {
+            Some(root) => match inside_root(root, filename.trim()) {
+                Some(path) => path,
+                None => return Err(format!("template \"{}\" is outside the template root", filename)),
+            },
+            None => PathBuf::from(filename.trim()),
+        };
+
+        let file = File::open(path);
         let mut file = match file {
             Ok(file)   => file,
 
             Err(error) => match error.kind() {
-                ErrorKind::NotFound => {
-                    let errmsg = String::from(format!("template \"{}\" not found", filename));
-                    self.errors.push(errmsg);
-                    return;
+                io::ErrorKind::NotFound => {
+                    return Err(format!("template \"{}\" not found", filename));
                 },
-                ErrorKind::PermissionDenied => {
-                    let errmsg = String::from(format!("lack privilege to open template {}", filename));
-                    self.errors.push(errmsg);
-                    return;
+                io::ErrorKind::PermissionDenied => {
+                    return Err(format!("lack privilege to open template {}", filename));
                 },
                 _ => {
-                    let errmsg = String::from(format!("unexpected error opening template {}: {}",
-                                                      filename, error));
-                    self.errors.push(errmsg);
-                    return;
+                    return Err(format!("unexpected error opening template {}: {}",
+                                       filename, error));
                 },
             }
         };
 
         let mut result = String::new();
         match file.read_to_string(&mut result) {
-            Ok(_) => self.template = Some(result),
-            Err(e) => {
-                let errmsg = String::from(format!("unexpected error reading template {}: {}",
-                                          filename, e));
-                self.errors.push(errmsg);
-            }
+            Ok(_) => Ok(result),
+            Err(e) => Err(format!("unexpected error reading template {}: {}", filename, e)),
         }
     }
 }
+
+// joins name to root, None when name is absolute or climbs out of root with ..
+fn inside_root(root: &Path, name: &str) -> Option<PathBuf> {
+    let mut depth: usize = 0;
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => (),
+            Component::ParentDir => depth = depth.checked_sub(1)?,
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    Some(root.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::thread;
+
+    use super::Amps;
+    use crate::engine::value::{Value, ValueMap};
+
+    // a sink that refuses every write
+    struct Broken;
+
+    impl io::Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn renders_the_exact_bytes() {
+        let mut amps = Amps::new();
+        amps.load_template(String::from("żółw {= 1 =}\n{= true =}\t{= \"x\" =}\r\n"));
+
+        let mut out: Vec<u8> = Vec::new();
+        amps.render_to(&mut out).unwrap();
+        assert_eq!(out, "żółw 1\ntrue\tx\r\n".as_bytes());
+        assert_eq!(amps.render_to_string().unwrap(), "żółw 1\ntrue\tx\r\n");
+    }
+
+    #[test]
+    fn render_errors() {
+        let amps = Amps::new();
+        let errors = amps.render_to_string().unwrap_err();
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(), vec!["no template available"]);
+
+        // the output is written before the errors are returned
+        let mut amps = Amps::new();
+        amps.load_template(String::from("a{= missing =}b"));
+        let mut out: Vec<u8> = Vec::new();
+        let errors = amps.render_to(&mut out).unwrap_err();
+        assert_eq!(out, b"anullb");
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(), vec!["invalid identifier missing"]);
+
+        let errors = amps.render_to(&mut Broken).unwrap_err();
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(),
+                   vec!["invalid identifier missing", "cannot write the rendered template: closed"]);
+
+        let mut amps = Amps::new();
+        amps.load_template_from_file("no/such/template.tpl");
+        assert_eq!(amps.get_errors().iter().map(|e| e.message()).collect::<Vec<_>>(),
+                   vec!["template \"no/such/template.tpl\" not found"]);
+    }
+
+    #[test]
+    fn variables_persist_across_renders() {
+        let mut amps = Amps::new();
+        amps.set("name", "Ann");
+        amps.set("age", 30);
+        amps.load_template(String::from("{= name =} {= age =}"));
+        assert_eq!(amps.render_to_string().unwrap(), "Ann 30");
+        assert_eq!(amps.render_to_string().unwrap(), "Ann 30");
+
+        amps.set("age", 31);
+        assert_eq!(amps.get("age"), Some(&Value::Int(31)));
+        assert_eq!(amps.render_to_string().unwrap(), "Ann 31");
+
+        // a set in the template doesn't change the variable
+        amps.load_template(String::from("{% set age = 99 %}{= age =}"));
+        assert_eq!(amps.render_to_string().unwrap(), "99");
+        assert_eq!(amps.get("age"), Some(&Value::Int(31)));
+
+        assert_eq!(amps.unset("age"), Some(Value::Int(31)));
+        assert_eq!(amps.unset("age"), None);
+        amps.load_template(String::from("{= name =}{= age =}"));
+        let errors = amps.render_to_string().unwrap_err();
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(), vec!["invalid identifier age"]);
+
+        let city: ValueMap = vec![(String::from("city"), Value::from("Oslo"))].into_iter().collect();
+        amps.extend(vec![("age", Value::from(5)), ("home", Value::Map(city))]);
+        amps.extend(vec![(String::from("tags"), vec!["a", "b"])]);
+        amps.load_template(String::from("{= name =} {= age =} {= home.city =} {= tags =} {= maybe =}"));
+        amps.set("maybe", None::<i64>);
+        assert_eq!(amps.render_to_string().unwrap(), "Ann 5 Oslo [\"a\", \"b\"] null");
+
+        amps.clear();
+        assert_eq!(amps.get("name"), None);
+        assert!(amps.render_to_string().is_err());
+    }
+
+    #[test]
+    fn amps_moves_across_threads() {
+        let mut amps = Amps::new();
+        amps.set("name", "Ann");
+        amps.load_template(String::from("Hi {= name =}"));
+
+        let output = thread::spawn(move || amps.render_to_string().unwrap()).join().unwrap();
+        assert_eq!(output, "Hi Ann");
+    }
+
+    #[test]
+    fn amps_renders_on_many_threads() {
+        let mut amps = Amps::new();
+        amps.load_template(String::from("{= \"a1\" matches \"[0-9]\" =}"));
+
+        let amps = &amps;
+        thread::scope(|scope| {
+            let renders: Vec<_> = (0..4).map(|_| scope.spawn(move || amps.render_to_string().unwrap())).collect();
+            for render in renders {
+                assert_eq!(render.join().unwrap(), "true");
+            }
+        });
+    }
+}
@@ -1,14 +1,16 @@
+use std::env;
+use std::io::{self, IsTerminal};
+
 use rustyline::Editor;
 
-use crate::amps::Amps;
+use ampsrs::amps::Amps;
 
 pub fn main_loop() {
     println!("Welcome to Amps cli.");
 
     let mut repl = Editor::<()>::new();
-    if repl.load_history("amps_history.txt").is_err() {
-
-    }
+    // a missing history file just means this is the first session
+    let _ = repl.load_history("amps_history.txt");
 
     let mut amps = Amps::new();
     loop {
@@ -46,7 +48,7 @@ pub fn main_loop() {
 fn parse_command(command: &str, amps: &mut Amps) {
     let command_with_args: Vec<&str> = command.split(' ').collect();
 
-    match command_with_args.first().unwrap().as_ref() {
+    match *command_with_args.first().unwrap() {
         "load"    => load(&command_with_args[1..], amps),
         "render"  => render(&command_with_args[1..], amps),
         _         => println!("invalid command: {}", command),
@@ -82,18 +84,29 @@ fn show_template(amps: &Amps) {
     }
 }
 
-fn render(args: &[&str], amps: &mut Amps) {
+fn render(_args: &[&str], amps: &mut Amps) {
+    let colors = use_colors();
     let mut i : usize = 0;
     for e in amps.get_errors().iter() {
-        print!("{:?}", e);
+        println!("{}", e.render(colors));
         i += 1;
     }
 
     if i == 0 {
-        amps.render();
+        if let Err(errors) = amps.render() {
+            for e in errors.iter() {
+                println!("{}", e.render(colors));
+            }
+        }
     }
 }
 
+// errors are painted only on a terminal, NO_COLOR turns colors off
+// everywhere, see https://no-color.org
+fn use_colors() -> bool {
+    io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+}
+
 fn help() {
     println!("Commands: ");
     println!(" - help:\tPrint this help");
@@ -1,35 +1,115 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::rc::Rc;
+use std::sync::Mutex;
+use regex::Regex;
+use super::error::{AmpsError, ErrorKind, Source, Span};
+use super::escape::Escape;
+use super::metadata;
 use super::token_types;
+use super::value::Value;
 
-pub enum StackType {
-    Number(i64),
-    Text(String),
-    Bool(bool),
+// Loader returns the source of a template referenced by name from another
+// template, like {% import "forms.tpl" %}
+pub type Loader<'a> = &'a dyn Fn(&str) -> Result<String, String>;
+
+// RegexCache holds the patterns a template compiled, it's kept with the
+// template so they're compiled once for every render of it. Renders on
+// different threads share it.
+pub type RegexCache = Mutex<HashMap<String, Regex>>;
+
+// patterns built while rendering can all be different, the cache is emptied
+// when it holds this many
+const MAX_CACHED_REGEXES: usize = 64;
+
+// Test is a predicate the host adds to Amps, templates use it like the
+// builtin ones: {% if x is name %}
+pub type Test = Box<dyn Fn(&Value) -> bool + Send + Sync>;
+
+// Macro is a template fragment defined by {% macro name(params) %} and
+// rendered every time it's called. Parameters without a default value are
+// required.
+pub struct Macro {
+    pub params: Vec<(String, Option<Value>)>,
+    pub body: metadata::Metainfo,
 }
 
-impl fmt::Debug for StackType {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct("StackType")
-            .finish()
+// Truthiness tells which values a condition accepts. Strict takes only
+// booleans, Lenient also takes any other value following Value::is_truthy,
+// so {% if items %} is false for an empty list
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Truthiness {
+    Strict,
+    Lenient,
+}
+
+// Settings are the knobs the host sets on Amps that change how templates
+// are rendered
+pub struct Settings {
+    pub max_iterations: usize,
+    pub max_macro_depth: usize,
+    pub tests: HashMap<String, Test>,
+    pub truthiness: Truthiness,
+    pub coercion: bool,
+    pub escape: Escape,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            max_iterations: 10_000,
+            max_macro_depth: 16,
+            tests: HashMap::new(),
+            truthiness: Truthiness::Strict,
+            coercion: false,
+            escape: Escape::Off,
+        }
     }
 }
 
-pub enum EnvType {
-    Number(i64),
-    Text(String),
-    NumberVector(Vec<i64>),
-    TextVector(Vec<String>),
-    NumberMap(HashMap<String, i64>),
-    TextMap(HashMap<String, String>),
+// Branch is one open block, like if, with or while. taken tells whether the
+// statements in the current clause run. For an if, decided tells whether some
+// clause was already taken, in which case every elif and else after it is
+// skipped; for a while, whether the loop is still running. A match keeps its
+// subject, evaluated once, and the values of the cases seen so far. An
+// autoescape keeps the escape to restore when it's closed. span and source
+// are where the block was opened.
+pub struct Branch {
+    pub kind: token_types::TokenTypes,
+    pub taken: bool,
+    pub decided: bool,
+    pub in_else: bool,
+    pub scope: bool,
+    pub span: Span,
+    pub source: Option<Source>,
+    pub start: usize,
+    pub iterations: usize,
+    pub subject: Option<Value>,
+    pub cases: Vec<(Value, usize)>,
+    pub escape: Option<Escape>,
 }
 
 pub struct Context<'a> {
-    the_stack: Vec<StackType>,
-    the_environment: HashMap<String, &'a EnvType>,
-    the_problems: Vec<String>,
-    branches: Vec<(token_types::TokenTypes, bool)>,
+    the_stack: Vec<Value>,
+    the_environment: HashMap<String, Value>,
+    the_scopes: Vec<HashMap<String, Value>>,
+    the_calls: Vec<usize>,
+    the_problems: Vec<AmpsError>,
+    suspended: usize,
+    branches: Vec<Branch>,
     program_counter: usize,
+    span: Span,
+    source: Option<Source>,
+    macros: HashMap<String, Rc<Macro>>,
+    captures: Vec<String>,
+    output: String,
+    imported: Vec<String>,
+    loader: Option<Loader<'a>>,
+    settings: Option<&'a Settings>,
+    regexes: Option<&'a RegexCache>,
+    escape: Escape,
+    nesting: usize,
+    too_deep: Option<usize>,
 }
 
 impl<'a> Context<'a> {
@@ -37,112 +117,434 @@ impl<'a> Context<'a> {
         Context {
             the_stack: Vec::new(),
             the_environment: HashMap::new(),
+            the_scopes: Vec::new(),
+            the_calls: Vec::new(),
             the_problems: Vec::new(),
+            suspended: 0,
             branches: Vec::new(),
             program_counter: 0,
+            span: Span::default(),
+            source: None,
+            macros: HashMap::new(),
+            captures: Vec::new(),
+            output: String::new(),
+            imported: Vec::new(),
+            loader: None,
+            settings: None,
+            regexes: None,
+            escape: Escape::Off,
+            nesting: 0,
+            too_deep: None,
         }
     }
 
-    pub fn branch_is_taken(&self) -> &(token_types::TokenTypes, bool) {
-        self.branches.last().unwrap_or(&(token_types::TokenTypes::INVALID, true))
+    pub fn set_settings(&mut self, settings: &'a Settings) {
+        self.settings = Some(settings);
+        self.escape = settings.escape;
     }
 
-    pub fn branch_push(&mut self, ttype: token_types::TokenTypes, cond: bool) {
-        self.branches.push((ttype, cond));
+    pub fn escape(&self) -> Escape {
+        self.escape
     }
 
-    pub fn branch_pop(&mut self) -> Result<(), ()> {
-        match self.branches.pop() {
-            Some(_) => Ok(()),
-            None => Err(()),
+    pub fn set_escape(&mut self, escape: Escape) {
+        self.escape = escape;
+    }
+
+    pub fn max_iterations(&self) -> usize {
+        match self.settings {
+            Some(settings) => settings.max_iterations,
+            None => Settings::default().max_iterations,
         }
     }
 
-    pub fn errors_push(&mut self, error: String) {
-        self.the_problems.push(error);
+    pub fn max_macro_depth(&self) -> usize {
+        match self.settings {
+            Some(settings) => settings.max_macro_depth,
+            None => Settings::default().max_macro_depth,
+        }
     }
 
-    pub fn errors_it(&self) -> std::slice::Iter<String> {
-        self.the_problems.iter()
+    pub fn truthiness(&self) -> Truthiness {
+        match self.settings {
+            Some(settings) => settings.truthiness,
+            None => Truthiness::Strict,
+        }
     }
 
-    pub fn stack_push(&mut self, data: StackType) {
-        self.the_stack.push(data);
+    pub fn coercion(&self) -> bool {
+        match self.settings {
+            Some(settings) => settings.coercion,
+            None => false,
+        }
     }
 
-    pub fn stack_pop(&mut self) -> Option<StackType> {
-        self.the_stack.pop()
+    // truth
+    //
+    // Returns the boolean a condition takes data as, None when data can't be
+    // a condition
+    pub fn truth(&self, data: &Value) -> Option<bool> {
+        match data {
+            Value::Bool(b) => Some(*b),
+            _ if self.truthiness() == Truthiness::Lenient => Some(data.is_truthy()),
+            _ => None,
+        }
     }
 
-    pub fn env_add_or_update(&mut self, key: &String, data: &'a EnvType) {
-        self.the_environment.entry(key.clone()).or_insert(data);
+    // runs the test called name added by the host, None if there isn't one
+    pub fn run_test(&self, name: &str, data: &Value) -> Option<bool> {
+        self.settings
+            .and_then(|settings| settings.tests.get(name))
+            .map(|test| test(data))
     }
 
-    pub fn env_get(&self, key: &String) -> Option<&'a EnvType> {
-        match self.the_environment.get(key) {
-            Some(data) => Some(*data),
-            None => None,
+    // regex
+    //
+    // Returns the compiled pattern, each pattern is compiled once for the
+    // template when there's a cache. A bad pattern is described with the
+    // line of the template using it and the column of the pattern where
+    // it's wrong.
+    pub fn regex(&mut self, pattern: &str) -> Result<Regex, String> {
+        // a render that panicked holding the lock left the cache usable
+        let cache = self.regexes.map(|cache| cache.lock().unwrap_or_else(|e| e.into_inner()));
+        if let Some(re) = cache.as_ref().and_then(|cache| cache.get(pattern)) {
+            return Ok(re.clone());
+        }
+
+        match Regex::new(pattern) {
+            Ok(re) => {
+                if let Some(mut cache) = cache {
+                    if cache.len() >= MAX_CACHED_REGEXES {
+                        cache.clear();
+                    }
+                    cache.insert(pattern.to_string(), re.clone());
+                }
+                Ok(re)
+            },
+            Err(e) => Err(format!("invalid pattern \"{}\" at line {}{}",
+                                  pattern, self.span.line, describe_regex_error(&e))),
         }
     }
 
-    pub fn env_key_exists(&self, key: &String) -> bool {
-        self.the_environment.contains_key(key)
+    pub fn set_regex_cache(&mut self, regexes: &'a RegexCache) {
+        self.regexes = Some(regexes);
     }
 
-    pub fn stack_push_from_env(&mut self, key: &String) {
-        if !self.env_key_exists(key) {
-            return;
+    pub fn set_loader(&mut self, loader: Loader<'a>) {
+        self.loader = Some(loader);
+    }
+
+    // load_template
+    //
+    // Returns the source of the template called name, a template is loaded
+    // only once per render so import cycles end by themselves
+    pub fn load_template(&mut self, name: &str) -> Option<Result<String, String>> {
+        if self.imported.iter().any(|n| n == name) {
+            return None;
         }
+        self.imported.push(name.to_string());
 
-        match self.the_environment.get(key).unwrap() {
-            EnvType::Number(n) => self.the_stack.push(StackType::Number(n.clone())),
-            EnvType::Text(t) => self.the_stack.push(StackType::Text(t.clone())),
-            _ => self.errors_push(String::from("complex variable, must be parsed")),
+        match self.loader {
+            Some(loader) => Some(loader(name)),
+            None => Some(Err(format!("cannot load template \"{}\", no loader available", name))),
         }
     }
 
-    pub fn stack_push_from_env_vector(&mut self, key: &String, id: usize) {
-        if !self.env_key_exists(key) {
-            return;
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    pub fn set_program_counter(&mut self, pc: usize) {
+        self.program_counter = pc;
+    }
+
+    // line of the statement being run, blocks remember where they started
+    pub fn line(&self) -> usize {
+        self.span.line
+    }
+
+    // where the statement being run is and the line of the template holding
+    // it, errors point there
+    pub fn position(&self) -> (Span, Option<Source>) {
+        (self.span, self.source.clone())
+    }
+
+    pub fn set_position(&mut self, span: Span, source: Option<Source>) {
+        self.span = span;
+        self.source = source;
+    }
+
+    pub fn branch_is_taken(&self) -> bool {
+        self.branches.last().is_none_or(|b| b.taken)
+    }
+
+    pub fn branch_push(&mut self, ttype: token_types::TokenTypes, taken: bool, decided: bool) {
+        self.branches.push(Branch {
+            kind: ttype,
+            taken,
+            decided,
+            in_else: false,
+            scope: false,
+            span: self.span,
+            source: self.source.clone(),
+            start: self.program_counter,
+            iterations: 0,
+            subject: None,
+            cases: Vec::new(),
+            escape: None,
+        });
+    }
+
+    // break_loop
+    //
+    // Stops running the statements of the innermost while and of every block
+    // opened inside it, up to its endwhile. When the loop is broken it's not
+    // evaluated again.
+    pub fn break_loop(&mut self, stop: bool) -> Result<(), ()> {
+        let at = match self.branches.iter().rposition(|b| b.kind == token_types::TokenTypes::WHILE) {
+            Some(at) => at,
+            None => return Err(()),
+        };
+
+        for b in self.branches[at..].iter_mut() {
+            b.taken = false;
         }
 
-        match self.the_environment.get(key).unwrap() {
-            EnvType::TextVector(v) => {
-                match v.get(id) {
-                    Some(t) => self.the_stack.push(StackType::Text(t.clone())),
-                    None => panic!("invalid id {}[{}]", key, id),
-                }
+        if stop {
+            self.branches[at].decided = false;
+        }
+        Ok(())
+    }
+
+    pub fn branch_top(&mut self) -> Option<&mut Branch> {
+        self.branches.last_mut()
+    }
+
+    pub fn branch_pop(&mut self) -> Option<Branch> {
+        self.branches.pop()
+    }
+
+    // a macro body runs with its own branches, the caller's ones are put
+    // aside and given back when it's done
+    pub fn branches_take(&mut self) -> Vec<Branch> {
+        std::mem::take(&mut self.branches)
+    }
+
+    pub fn branches_restore(&mut self, branches: Vec<Branch>) {
+        self.branches = branches;
+    }
+
+    // how deep the expression being evaluated is nested, counting the ones
+    // in macros being called
+    pub fn nesting(&self) -> usize {
+        self.nesting
+    }
+
+    pub fn nesting_push(&mut self) {
+        self.nesting += 1;
+    }
+
+    pub fn nesting_pop(&mut self) {
+        self.nesting = self.nesting.saturating_sub(1);
+    }
+
+    // too_deep
+    //
+    // Reports that an expression is nested too deep. Everything the nested
+    // levels report while they give up is noise, like a missing ) for each of
+    // them, so it's dropped once the outermost level is done and the error
+    // reported here is the only one left.
+    pub fn too_deep(&mut self, error: String) {
+        if self.too_deep.is_none() && !self.is_suspended() {
+            self.too_deep = Some(self.the_problems.len());
+            self.errors_push(ErrorKind::Limit, error);
+        }
+    }
+
+    pub fn too_deep_done(&mut self) {
+        if self.nesting == 0 {
+            if let Some(at) = self.too_deep.take() {
+                self.the_problems.truncate(at + 1);
+            }
+        }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.suspended > 0
+    }
+
+    pub fn macro_add(&mut self, name: String, mac: Macro) {
+        self.macros.insert(name, Rc::new(mac));
+    }
+
+    pub fn macro_get(&self, name: &str) -> Option<Rc<Macro>> {
+        self.macros.get(name).cloned()
+    }
+
+    pub fn capture_depth(&self) -> usize {
+        self.captures.len()
+    }
+
+    // output written while capturing goes to a buffer of its own instead of
+    // the rendered template, it's how macros render into a value
+    pub fn capture_begin(&mut self) {
+        self.captures.push(String::new());
+    }
+
+    pub fn capture_end(&mut self) -> String {
+        self.captures.pop().unwrap_or_default()
+    }
+
+    pub fn write(&mut self, data: &str) {
+        match self.captures.last_mut() {
+            Some(buffer) => buffer.push_str(data),
+            None => self.output.push_str(data),
+        }
+    }
+
+    // returns what the template rendered so far
+    pub fn output_take(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+
+    // scopes hold local variables, like macro arguments. The innermost scope
+    // shadows the ones below it and all of them shadow the environment.
+    pub fn scope_push(&mut self, scope: HashMap<String, Value>) {
+        self.the_scopes.push(scope);
+    }
+
+    // the scope of a macro call, set doesn't look past it for the variable
+    // to change
+    pub fn scope_push_call(&mut self, scope: HashMap<String, Value>) {
+        self.the_calls.push(self.the_scopes.len());
+        self.the_scopes.push(scope);
+    }
+
+    pub fn scope_pop(&mut self) {
+        self.the_scopes.pop();
+        if self.the_calls.last() == Some(&self.the_scopes.len()) {
+            self.the_calls.pop();
+        }
+    }
+
+    // binds key in the innermost scope, it's an error to call it with no
+    // scope open
+    pub fn scope_set(&mut self, key: &str, data: Value) -> Result<(), ()> {
+        match self.the_scopes.last_mut() {
+            Some(scope) => {
+                scope.insert(key.to_string(), data);
+                Ok(())
             },
-            EnvType::NumberVector(v) => {
-                match v.get(id) {
-                    Some(t) => self.the_stack.push(StackType::Number(t.clone())),
-                    None => panic!("invalid id {}[{}]", key, id),
-                }
+            None => Err(()),
+        }
+    }
+
+    // scope_assign
+    //
+    // Gives key a new value where it's bound, looking from the innermost
+    // scope outwards like env_get does but not past the scope of the macro
+    // call. A key bound nowhere is bound in the innermost scope, or for the
+    // rest of the render when no scope is open.
+    pub fn scope_assign(&mut self, key: &str, data: Value) {
+        let first = self.the_calls.last().copied().unwrap_or(0);
+        let bound = self.the_scopes[first..].iter().rposition(|scope| scope.contains_key(key));
+        let global = self.the_calls.is_empty() && self.the_environment.contains_key(key);
+
+        match (bound, self.the_scopes.len()) {
+            (Some(at), _) => {
+                self.the_scopes[first + at].insert(key.to_string(), data);
+            },
+            (None, len) if len == 0 || global => self.env_add_or_update(key, data),
+            (None, len) => {
+                self.the_scopes[len - 1].insert(key.to_string(), data);
             },
-            _ => panic!("complex variable, must be parsed"),
         }
     }
 
-    pub fn stack_push_from_env_map(&mut self, key: &String, id: &String) {
-        if !self.env_key_exists(key) {
+    // errors_push
+    //
+    // Reports an error of kind in the statement being run
+    pub fn errors_push(&mut self, kind: ErrorKind, error: String) {
+        let source = self.source.clone();
+        self.errors_push_at(kind, error, self.span, source);
+    }
+
+    // reports an error of kind in the statement at span, source is the line
+    // of the template holding it
+    pub fn errors_push_at(&mut self, kind: ErrorKind, error: String, span: Span, source: Option<Source>) {
+        if self.suspended > 0 {
             return;
         }
 
-        match self.the_environment.get(key).unwrap() {
-            EnvType::TextMap(v) => {
-                match v.get(id) {
-                    Some(t) => self.the_stack.push(StackType::Text(t.clone())),
-                    None => panic!("invalid id {}[{}]", key, id),
-                }
-            },
-            EnvType::NumberMap(v) => {
-                match v.get(id) {
-                    Some(t) => self.the_stack.push(StackType::Number(t.clone())),
-                    None => panic!("invalid id {}[{}]", key, id),
-                }
-            },
-            _ => panic!("complex variable, must be parsed"),
+        let error = match source {
+            Some(source) => AmpsError::at(kind, error, span, source),
+            None => AmpsError::new(kind, error),
+        };
+        self.the_problems.push(error);
+    }
+
+    // while evaluation is suspended expressions are still parsed, to move
+    // past them, but nothing they do is reported
+    pub fn suspend_evaluation(&mut self) {
+        self.suspended += 1;
+    }
+
+    pub fn resume_evaluation(&mut self) {
+        self.suspended = self.suspended.saturating_sub(1);
+    }
+
+    pub fn errors_count(&self) -> usize {
+        self.the_problems.len()
+    }
+
+    // drops the errors reported after the first count ones, used when an
+    // error is the answer to a question, like x is defined
+    pub fn errors_truncate(&mut self, count: usize) {
+        self.the_problems.truncate(count);
+    }
+
+    pub fn errors_take(&mut self) -> Vec<AmpsError> {
+        std::mem::take(&mut self.the_problems)
+    }
+
+    pub fn stack_push(&mut self, data: Value) {
+        self.the_stack.push(data);
+    }
+
+    pub fn stack_pop(&mut self) -> Option<Value> {
+        self.the_stack.pop()
+    }
+
+    pub fn env_add_or_update(&mut self, key: &str, data: Value) {
+        self.the_environment.insert(key.to_string(), data);
+    }
+
+    pub fn env_get(&self, key: &str) -> Option<&Value> {
+        for scope in self.the_scopes.iter().rev() {
+            if let Some(data) = scope.get(key) {
+                return Some(data);
+            }
         }
+
+        self.the_environment.get(key)
+    }
+}
+
+// regex reports syntax errors as the pattern with a ^ under the bad part
+// followed by the reason, this turns it into a single line
+fn describe_regex_error(error: &regex::Error) -> String {
+    let message = error.to_string();
+    let lines: Vec<&str> = message.lines().collect();
+
+    let column = lines.iter()
+        .find(|l| !l.trim().is_empty() && l.trim().chars().all(|c| c == '^' || c == '-'))
+        .and_then(|l| l.find('^'))
+        .map(|at| at.saturating_sub(4) + 1);
+    let reason = lines.last().map(|l| l.trim_start_matches("error: ")).unwrap_or("");
+
+    match column {
+        Some(column) => format!(", column {}: {}", column, reason),
+        None => format!(": {}", reason),
     }
 }
 
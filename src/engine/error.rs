@@ -0,0 +1,241 @@
+use std::fmt;
+use std::sync::Arc;
+
+// ErrorKind is what went wrong, each kind has its own code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    // the template can't be understood: an unknown statement, a missing ),
+    // a block closed twice
+    Syntax,
+    // a variable, attribute, index, function, macro or test that doesn't exist
+    Lookup,
+    // an operator, test or function that can't handle the values it got,
+    // like a division by 0 or a text added to an int
+    Operation,
+    // the template went over a limit: too many iterations, too deep
+    Limit,
+    // a template that can't be loaded, extended or imported
+    Template,
+    // the rendered template can't be written
+    Io,
+}
+
+impl ErrorKind {
+    pub fn code(self) -> &'static str {
+        match self {
+            ErrorKind::Syntax    => "E0001",
+            ErrorKind::Lookup    => "E0002",
+            ErrorKind::Operation => "E0003",
+            ErrorKind::Limit     => "E0004",
+            ErrorKind::Template  => "E0005",
+            ErrorKind::Io        => "E0006",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error   => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+// Span is where a block is in its template. line and column start at 1,
+// column and length count characters.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+// Source is the line of a template a block was found in, without its line
+// break. template is the name the template was loaded by, None for one
+// given to Amps as text.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub template: Option<Arc<str>>,
+    pub line: Arc<str>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AmpsError {
+    kind: ErrorKind,
+    severity: Severity,
+    message: String,
+    span: Option<Span>,
+    source: Option<Source>,
+}
+
+impl AmpsError {
+    pub fn new(kind: ErrorKind, message: String) -> AmpsError {
+        AmpsError {
+            kind,
+            severity: Severity::Error,
+            message,
+            span: None,
+            source: None,
+        }
+    }
+
+    // the error found in the block at span, source is the line holding it
+    pub fn at(kind: ErrorKind, message: String, span: Span, source: Source) -> AmpsError {
+        AmpsError {
+            span: Some(span),
+            source: Some(source),
+            ..AmpsError::new(kind, message)
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn code(&self) -> &'static str {
+        self.kind.code()
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    // the name of the template the error was found in, None for the one
+    // given to Amps as text
+    pub fn template(&self) -> Option<&str> {
+        self.source.as_ref().and_then(|s| s.template.as_deref())
+    }
+
+    // render
+    //
+    // Returns the error with the line it was found in and the block that
+    // caused it underlined, like
+    //
+    //   error[E0003]: mismatch types int(1) PLUS text(a)
+    //    --> report.tpl, line 3, column 8
+    //     |
+    //   3 | Total: {= 1 + "a" =}
+    //     |        ^^^^^^^^^^^^^
+    //
+    // colors paints it with ANSI escapes, for terminals
+    pub fn render(&self, colors: bool) -> String {
+        let paint = |code: &str, text: &str| {
+            if colors {
+                format!("\x1b[{}m{}\x1b[0m", code, text)
+            }
+            else {
+                text.to_string()
+            }
+        };
+
+        let severity_color = match self.severity {
+            Severity::Error   => "1;31",
+            Severity::Warning => "1;33",
+        };
+        let mut ret = format!("{}: {}",
+                              paint(severity_color, &format!("{}[{}]", self.severity, self.code())),
+                              paint("1", &self.message));
+
+        let (span, source) = match (self.span, &self.source) {
+            (Some(span), Some(source)) => (span, &source.line),
+            _ => return ret,
+        };
+        let template = match self.template() {
+            Some(name) => format!("{}, ", name),
+            None => String::new(),
+        };
+
+        let number = span.line.to_string();
+        let gutter = " ".repeat(number.len());
+
+        // tabs are kept under the caret so it lines up with the source
+        let indent: String = source.chars()
+            .take(span.column.saturating_sub(1))
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        let carets = "^".repeat(span.length.max(1));
+
+        ret.push_str(&format!("\n{}{} {}line {}, column {}", gutter, paint("1;34", "-->"), template, span.line, span.column));
+        ret.push_str(&format!("\n{} {}", gutter, paint("1;34", "|")));
+        ret.push_str(&format!("\n{} {} {}", paint("1;34", &number), paint("1;34", "|"), source));
+        ret.push_str(&format!("\n{} {} {}{}", gutter, paint("1;34", "|"), indent, paint(severity_color, &carets)));
+        ret
+    }
+}
+
+impl fmt::Display for AmpsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.render(false))
+    }
+}
+
+impl std::error::Error for AmpsError {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{AmpsError, ErrorKind, Source, Span};
+
+    fn source(template: Option<&str>, line: &str) -> Source {
+        Source {
+            template: template.map(Arc::from),
+            line: Arc::from(line),
+        }
+    }
+
+    #[test]
+    fn renders_the_line_with_a_caret() {
+        let span = Span { line: 3, column: 8, length: 13 };
+        let error = AmpsError::at(ErrorKind::Operation, String::from("mismatch types int(1) PLUS text(a)"),
+                                  span, source(None, "Total: {= 1 + \"a\" =}"));
+        assert_eq!(error.code(), "E0003");
+        assert_eq!(error.to_string(), concat!("error[E0003]: mismatch types int(1) PLUS text(a)\n",
+                                              " --> line 3, column 8\n",
+                                              "  |\n",
+                                              "3 | Total: {= 1 + \"a\" =}\n",
+                                              "  |        ^^^^^^^^^^^^^"));
+
+        // tabs before the block stay tabs under it
+        let span = Span { line: 12, column: 3, length: 1 };
+        let error = AmpsError::at(ErrorKind::Lookup, String::from("invalid identifier x"),
+                                  span, source(Some("base.tpl"), "\t\tx"));
+        assert_eq!(error.template(), Some("base.tpl"));
+        assert_eq!(error.to_string(), concat!("error[E0002]: invalid identifier x\n",
+                                              "  --> base.tpl, line 12, column 3\n",
+                                              "   |\n",
+                                              "12 | \t\tx\n",
+                                              "   | \t\t^"));
+    }
+
+    #[test]
+    fn renders_errors_without_a_span() {
+        let error = AmpsError::new(ErrorKind::Template, String::from("no template available"));
+        assert_eq!(error.to_string(), "error[E0005]: no template available");
+        assert_eq!(error.render(true), "\x1b[1;31merror[E0005]\x1b[0m: \x1b[1mno template available\x1b[0m");
+        assert_eq!(error.template(), None);
+    }
+
+    #[test]
+    fn errors_cross_threads() {
+        let error: Box<dyn std::error::Error + Send + Sync> =
+            Box::new(AmpsError::new(ErrorKind::Io, String::from("broken pipe")));
+        let message = std::thread::spawn(move || error.to_string()).join().unwrap();
+        assert_eq!(message, "error[E0006]: broken pipe");
+    }
+}
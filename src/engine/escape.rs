@@ -0,0 +1,112 @@
+use std::path::Path;
+
+// Escape is the format echoed values are escaped for, so a value can't
+// break the document it's rendered into. Text written in the template
+// itself is never escaped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Escape {
+    Off,
+    Html,
+    Xml,
+    Json,
+    Shell,
+}
+
+impl Escape {
+    // the escape called name in {% autoescape name %}
+    pub fn from_name(name: &str) -> Option<Escape> {
+        match name {
+            "off"   => Some(Escape::Off),
+            "html"  => Some(Escape::Html),
+            "xml"   => Some(Escape::Xml),
+            "json"  => Some(Escape::Json),
+            "shell" => Some(Escape::Shell),
+            _ => None,
+        }
+    }
+
+    // the escape a template file needs, picked by its extension
+    pub fn from_extension(filename: &str) -> Escape {
+        let extension = Path::new(filename.trim())
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+
+        match extension.as_deref() {
+            Some("html") | Some("htm") => Escape::Html,
+            Some("xml") | Some("svg")  => Escape::Xml,
+            Some("json")               => Escape::Json,
+            Some("sh") | Some("bash")  => Escape::Shell,
+            _ => Escape::Off,
+        }
+    }
+
+    // apply
+    //
+    // Returns text escaped for the format. Json escapes the content of a
+    // string, the quotes around it belong to the template. Shell quotes the
+    // whole text as a single word.
+    pub fn apply(self, text: &str) -> String {
+        match self {
+            Escape::Off => text.to_string(),
+            Escape::Html | Escape::Xml => {
+                let apostrophe = if self == Escape::Html { "&#x27;" } else { "&apos;" };
+                let mut ret = String::with_capacity(text.len());
+                for ch in text.chars() {
+                    match ch {
+                        '&'  => ret.push_str("&amp;"),
+                        '<'  => ret.push_str("&lt;"),
+                        '>'  => ret.push_str("&gt;"),
+                        '"'  => ret.push_str("&quot;"),
+                        '\'' => ret.push_str(apostrophe),
+                        _ => ret.push(ch),
+                    }
+                }
+                ret
+            },
+            Escape::Json => {
+                let mut ret = String::with_capacity(text.len());
+                for ch in text.chars() {
+                    match ch {
+                        '"'  => ret.push_str("\\\""),
+                        '\\' => ret.push_str("\\\\"),
+                        '\n' => ret.push_str("\\n"),
+                        '\r' => ret.push_str("\\r"),
+                        '\t' => ret.push_str("\\t"),
+                        c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+                        _ => ret.push(ch),
+                    }
+                }
+                ret
+            },
+            Escape::Shell => format!("'{}'", text.replace('\'', "'\\''")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Escape;
+
+    #[test]
+    fn escapes_for_each_format() {
+        let text = "<a href=\"x\">Tom & Jerry's</a>\n";
+        assert_eq!(Escape::Off.apply(text), text);
+        assert_eq!(Escape::Html.apply(text), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#x27;s&lt;/a&gt;\n");
+        assert_eq!(Escape::Xml.apply(text), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&apos;s&lt;/a&gt;\n");
+        assert_eq!(Escape::Json.apply(text), "<a href=\\\"x\\\">Tom & Jerry's</a>\\n");
+        assert_eq!(Escape::Json.apply("a\\b\u{1}"), "a\\\\b\\u0001");
+        assert_eq!(Escape::Shell.apply("it's; rm -rf /"), "'it'\\''s; rm -rf /'");
+    }
+
+    #[test]
+    fn picks_the_escape_by_name_and_extension() {
+        assert_eq!(Escape::from_name("json"), Some(Escape::Json));
+        assert_eq!(Escape::from_name("yaml"), None);
+        assert_eq!(Escape::from_extension("page.HTML"), Escape::Html);
+        assert_eq!(Escape::from_extension(" feed.xml\n"), Escape::Xml);
+        assert_eq!(Escape::from_extension("deploy.sh"), Escape::Shell);
+        assert_eq!(Escape::from_extension("notes.txt"), Escape::Off);
+        assert_eq!(Escape::from_extension("Makefile"), Escape::Off);
+    }
+}
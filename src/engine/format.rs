@@ -0,0 +1,234 @@
+use super::value::Value;
+
+// the widest a value can be laid out and the most digits it can have after
+// the point, so a spec can't make the engine allocate unbounded memory
+const MAX_WIDTH: usize = 4096;
+
+// Spec is a parsed format specification, the part after : in {= x:>10.2 =}
+//
+//   [[fill]align][sign]['#']['0'][width]['.' precision][type]
+//
+// align is one of < ^ >, sign is + or -, # adds the 0x, 0o or 0b prefix, 0
+// pads numbers with zeros after their sign and type is one of x X o b.
+struct Spec {
+    fill: char,
+    align: Option<char>,
+    plus: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    radix: Option<char>,
+}
+
+// format_value
+//
+// Returns data as text laid out following spec. Numbers are aligned to the
+// right by default, anything else to the left.
+pub fn format_value(data: &Value, spec: &str) -> Result<String, String> {
+    let spec = parse_spec(spec)?;
+
+    let (sign, body, numeric) = match (data, spec.radix) {
+        (Value::Int(n), Some(radix)) => {
+            let magnitude = (*n as i128).unsigned_abs();
+            let (digits, prefix) = match radix {
+                'x' => (format!("{:x}", magnitude), "0x"),
+                'X' => (format!("{:X}", magnitude), "0x"),
+                'o' => (format!("{:o}", magnitude), "0o"),
+                _   => (format!("{:b}", magnitude), "0b"),
+            };
+            let prefix = if spec.alternate { prefix } else { "" };
+            (number_sign(*n < 0, spec.plus) + prefix, digits, true)
+        },
+        (_, Some(radix)) => {
+            return Err(format!("format {} expects an int, not {}", radix, data.type_name()));
+        },
+        (Value::Int(n), None) => match spec.precision {
+            Some(p) => (number_sign(*n < 0, spec.plus), format!("{:.*}", p, (*n as f64).abs()), true),
+            None => (number_sign(*n < 0, spec.plus), (*n as i128).unsigned_abs().to_string(), true),
+        },
+        (Value::Float(f), None) => {
+            let body = match spec.precision {
+                Some(p) => format!("{:.*}", p, f.abs()),
+                None => Value::Float(f.abs()).to_string(),
+            };
+            (number_sign(f.is_sign_negative() && *f != 0.0, spec.plus), body, true)
+        },
+        (Value::Text(t), None) | (Value::Safe(t), None) => match spec.precision {
+            Some(p) => (String::new(), t.chars().take(p).collect(), false),
+            None => (String::new(), t.clone(), false),
+        },
+        (_, None) if spec.precision.is_some() => {
+            return Err(format!("precision is invalid for {}", data.type_name()));
+        },
+        (_, None) => (String::new(), data.to_string(), false),
+    };
+
+    let length = sign.chars().count() + body.chars().count();
+    if length >= spec.width {
+        return Ok(format!("{}{}", sign, body));
+    }
+    let padding = spec.width - length;
+
+    // zeros go between the sign, with the radix prefix, and the digits, like
+    // -0042 or 0x00ff
+    if spec.zero && numeric && spec.align.is_none() {
+        return Ok(format!("{}{}{}", sign, "0".repeat(padding), body));
+    }
+
+    let align = spec.align.unwrap_or(if numeric { '>' } else { '<' });
+    let (left, right) = match align {
+        '<' => (0, padding),
+        '^' => (padding / 2, padding - padding / 2),
+        _   => (padding, 0),
+    };
+
+    let fill = spec.fill.to_string();
+    Ok(format!("{}{}{}{}", fill.repeat(left), sign, body, fill.repeat(right)))
+}
+
+fn number_sign(negative: bool, plus: bool) -> String {
+    if negative {
+        String::from("-")
+    }
+    else if plus {
+        String::from("+")
+    }
+    else {
+        String::new()
+    }
+}
+
+fn parse_spec(spec: &str) -> Result<Spec, String> {
+    let chars: Vec<char> = spec.chars().collect();
+    let mut ret = Spec {
+        fill: ' ',
+        align: None,
+        plus: false,
+        alternate: false,
+        zero: false,
+        width: 0,
+        precision: None,
+        radix: None,
+    };
+
+    let is_align = |ch: Option<&char>| matches!(ch, Some('<') | Some('^') | Some('>'));
+    let mut at = 0;
+    if is_align(chars.get(1)) {
+        ret.fill = chars[0];
+        ret.align = Some(chars[1]);
+        at = 2;
+    }
+    else if is_align(chars.first()) {
+        ret.align = Some(chars[0]);
+        at = 1;
+    }
+
+    match chars.get(at) {
+        Some('+') => { ret.plus = true; at += 1; },
+        Some('-') => at += 1,
+        _ => (),
+    }
+
+    if chars.get(at) == Some(&'#') {
+        ret.alternate = true;
+        at += 1;
+    }
+
+    if chars.get(at) == Some(&'0') {
+        ret.zero = true;
+        at += 1;
+    }
+
+    let (width, next) = parse_digits(&chars, at, spec)?;
+    ret.width = width.unwrap_or(0);
+    at = next;
+
+    if chars.get(at) == Some(&'.') {
+        let (precision, next) = parse_digits(&chars, at + 1, spec)?;
+        if precision.is_none() {
+            return Err(format!("missing precision in format spec \"{}\"", spec));
+        }
+        ret.precision = precision;
+        at = next;
+    }
+
+    if let Some(&ch) = chars.get(at) {
+        if !matches!(ch, 'x' | 'X' | 'o' | 'b') {
+            return Err(format!("invalid format spec \"{}\"", spec));
+        }
+        ret.radix = Some(ch);
+        at += 1;
+    }
+
+    if at != chars.len() {
+        return Err(format!("invalid format spec \"{}\"", spec));
+    }
+
+    if ret.width > MAX_WIDTH || ret.precision.unwrap_or(0) > MAX_WIDTH {
+        return Err(format!("format spec \"{}\" is wider than {}", spec, MAX_WIDTH));
+    }
+    Ok(ret)
+}
+
+// reads the number starting at position at, returns it with the position
+// right after it
+fn parse_digits(chars: &[char], at: usize, spec: &str) -> Result<(Option<usize>, usize), String> {
+    let digits: String = chars[at.min(chars.len())..].iter()
+        .take_while(|ch| ch.is_ascii_digit())
+        .collect();
+
+    let next = at + digits.len();
+    if digits.is_empty() {
+        return Ok((None, next));
+    }
+
+    match digits.parse() {
+        Ok(n) => Ok((Some(n), next)),
+        Err(_) => Err(format!("number {} out of range in format spec \"{}\"", digits, spec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_value;
+    use crate::engine::value::Value;
+
+    #[test]
+    fn lays_out_values() {
+        let cases: &[(Value, &str, &str)] = &[
+            (Value::Float(1.23456), ">10.2", "      1.23"),
+            (Value::Int(42), "08", "00000042"),
+            (Value::Int(-42), "08", "-0000042"),
+            (Value::Int(255), "#x", "0xff"),
+            (Value::Int(255), "X", "FF"),
+            (Value::Int(8), "#o", "0o10"),
+            (Value::Int(5), "#010b", "0b00000101"),
+            (Value::Int(-255), "#x", "-0xff"),
+            (Value::Int(7), "+", "+7"),
+            (Value::Float(2.5), "+.1", "+2.5"),
+            (Value::Int(7), "5", "    7"),
+            (Value::from("ab"), "5", "ab   "),
+            (Value::from("ab"), "*^6", "**ab**"),
+            (Value::from("ab"), "->4", "--ab"),
+            (Value::from("żółw"), "<6", "żółw  "),
+            (Value::Int(3), ".2", "3.00"),
+            (Value::from("abcdef"), ".3", "abc"),
+            (Value::Bool(true), ">6", "  true"),
+        ];
+        for (data, spec, expected) in cases {
+            assert_eq!(format_value(data, spec).unwrap(), *expected, "{:?}:{}", data, spec);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_specs() {
+        assert_eq!(format_value(&Value::Float(1.0), "x").unwrap_err(), "format x expects an int, not float");
+        assert_eq!(format_value(&Value::Null, ".2").unwrap_err(), "precision is invalid for null");
+        assert_eq!(format_value(&Value::Int(1), "5.").unwrap_err(), "missing precision in format spec \"5.\"");
+        assert_eq!(format_value(&Value::Int(1), ">5q").unwrap_err(), "invalid format spec \">5q\"");
+        assert_eq!(format_value(&Value::Int(1), "5000").unwrap_err(), "format spec \"5000\" is wider than 4096");
+        assert_eq!(format_value(&Value::Int(1), "99999999999999999999").unwrap_err(),
+                   "number 99999999999999999999 out of range in format spec \"99999999999999999999\"");
+    }
+}
@@ -1,8 +1,10 @@
 use std::fmt;
+use super::error::{Source, Span};
 use super::token;
 use super::token_types;
 
-#[derive(Debug)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
 pub enum Metatype {
     TEXT,
     CODE,
@@ -10,21 +12,50 @@ pub enum Metatype {
     COMMENT,
 }
 
+#[derive(Clone)]
 pub struct Metadata {
     mtype: Metatype,
     data: String,
     tokens: Option<Vec<token::Token>>,
+    span: Span,
+    source: Source,
 }
 
+#[allow(dead_code)]
 impl Metadata {
-    pub fn new(t: Metatype, d: String, tks: Option<Vec<token::Token>>) -> Metadata {
+    pub fn new(t: Metatype, d: String, tks: Option<Vec<token::Token>>, span: Span, source: Source) -> Metadata {
         Metadata {
             mtype: t,
             data: d,
             tokens: tks,
+            span,
+            source,
         }
     }
 
+    // a copy of this metadata holding tokens instead
+    pub fn with_tokens(&self, tks: Vec<token::Token>) -> Metadata {
+        Metadata {
+            tokens: Some(tks),
+            ..self.clone()
+        }
+    }
+
+    // line in the template where this metadata was found, starting at 1
+    pub fn get_line(&self) -> usize {
+        self.span.line
+    }
+
+    // where this metadata is in its template
+    pub fn get_span(&self) -> Span {
+        self.span
+    }
+
+    // the line of the template holding this metadata
+    pub fn get_source(&self) -> &Source {
+        &self.source
+    }
+
     pub fn get_tokens(&self) -> &Option<Vec<token::Token>> {
         &self.tokens
     }
@@ -44,7 +75,7 @@ pub struct Tokenator<'a> {
 }
 
 impl<'a> Tokenator<'a> {
-    pub fn new(tokens: &Vec<token::Token>) -> Tokenator {
+    pub fn new(tokens: &Vec<token::Token>) -> Tokenator<'_> {
         Tokenator {
             buffer: tokens,
             index: 0,
@@ -57,6 +88,9 @@ pub trait TokenatorTrait {
     fn look(&self)       -> Option<&token::Token>;
     fn look_back(&self)  -> Option<&token::Token>;
     fn look_ahead(&self) -> Option<&token::Token>;
+    fn look_nth(&self, n: usize) -> Option<&token::Token>;
+    fn position(&self) -> usize;
+    fn seek(&mut self, index: usize);
     fn skip_all(&mut self);
     fn match_next(&mut self, token_type: token_types::TokenTypes) -> bool;
 }
@@ -78,29 +112,36 @@ impl<'a> TokenatorTrait for Tokenator<'a> {
     }
 
     fn look_back(&self) -> Option<&token::Token> {
-        self.buffer.get(self.index - 1)
+        self.buffer.get(self.index.checked_sub(1)?)
     }
 
     fn look_ahead(&self) -> Option<&token::Token> {
         self.buffer.get(self.index + 1)
     }
 
+    fn look_nth(&self, n: usize) -> Option<&token::Token> {
+        self.buffer.get(self.index + n)
+    }
+
+    fn position(&self) -> usize {
+        self.index
+    }
+
+    fn seek(&mut self, index: usize) {
+        self.index = index.min(self.buffer.len());
+    }
+
     fn skip_all(&mut self) {
         self.index = self.buffer.len();
     }
 
     fn match_next(&mut self, token_type: token_types::TokenTypes) -> bool {
         match self.buffer.get(self.index) {
-            Some(tk) => {
-                if tk.get_type() == token_type {
-                    self.next();
-                    true
-                }
-                else {
-                    false
-                }
+            Some(tk) if tk.get_type() == token_type => {
+                self.next();
+                true
             },
-            None => false,
+            _ => false,
         }
     }
 }
@@ -113,6 +154,7 @@ impl fmt::Debug for Metadata {
             .field("mtype", &self.mtype)
             .field("data", &self.data)
             .field("tokens", &self.tokens)
+            .field("span", &self.span)
             .finish()
     }
 }
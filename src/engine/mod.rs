@@ -3,5 +3,9 @@ mod token;
 mod metadata;
 pub mod scan;
 
-mod context;
+pub mod context;
+pub mod value;
+mod format;
+pub mod escape;
+pub mod error;
 pub mod parser;
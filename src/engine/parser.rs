@@ -1,71 +1,456 @@
 pub mod parsing {
+    use std::borrow::Cow;
+    use std::collections::HashMap;
+
     use crate::engine::metadata;
     use crate::engine::context;
-    use crate::engine::context::StackType;
+    use crate::engine::error::{AmpsError, ErrorKind};
+    use crate::engine::escape::Escape;
+    use crate::engine::format;
+    use crate::engine::scan;
+    use crate::engine::token;
+    use crate::engine::value::{Value, ValueMap};
     use crate::engine::metadata::TokenatorTrait;
     use crate::engine::token_types::TokenTypes;
 
-    pub fn parse(meta: &metadata::Metainfo) {
+    // how deep expressions can be nested, like ((((1)))) or - - - 1, counting
+    // the expressions in the macros being called and each call as one more
+    // level. A level takes about 16 KiB of stack in a debug build, so even a
+    // thread with the 2 MiB stack of std::thread::spawn has room to spare.
+    const MAX_EXPRESSION_DEPTH: usize = 64;
+
+    // parse
+    //
+    // Renders meta with variables, returns the rendered text and the errors
+    // found. The text is returned even when there are errors, whatever
+    // failed renders as null or nothing at all.
+    pub fn parse(meta: &metadata::Metainfo, loader: context::Loader, settings: &context::Settings,
+                 variables: &HashMap<String, Value>, regexes: &context::RegexCache) -> (String, Vec<AmpsError>) {
         let mut ctx: context::Context = context::Context::new();
+        ctx.set_loader(loader);
+        ctx.set_regex_cache(regexes);
+        ctx.set_settings(settings);
+        for (name, data) in variables {
+            ctx.env_add_or_update(name, data.clone());
+        }
+
+        let program = compile(meta, &mut ctx);
+        execute(&program, &mut ctx);
+        close_branches(&mut ctx);
+        //println!("{:#?}", ctx);
+
+        (ctx.output_take(), ctx.errors_take())
+    }
+
+    // compile
+    //
+    // Resolves template inheritance before anything is rendered. When meta
+    // extends another template, the root of the extends chain becomes the
+    // skeleton and each of its blocks is replaced by the most derived version
+    // of that block. What a child renders outside its blocks is discarded,
+    // but its macros and imports are kept.
+    fn compile(meta: &[metadata::Metadata], ctx: &mut context::Context) -> metadata::Metainfo {
+        let mut overrides: HashMap<String, Vec<metadata::Metainfo>> = HashMap::new();
+        let mut current = meta.to_vec();
+
+        while let Some((parent, extends)) = find_extends(&current, ctx) {
+            report_duplicate_blocks(&current, ctx);
+            collect_blocks(&current, &mut overrides);
+
+            let prelude = strip_blocks(&current);
+            execute_isolated(&prelude, ctx);
+
+            locate(&extends, ctx);
+            current = match ctx.load_template(&parent) {
+                Some(Ok(source)) => scan::scanner::scan(&source, Some(&parent)),
+                Some(Err(e)) => {
+                    ctx.errors_push(ErrorKind::Template, e);
+                    return Vec::new();
+                },
+                None => {
+                    ctx.errors_push(ErrorKind::Template, format!("template \"{}\" is extended in a cycle", parent));
+                    return Vec::new();
+                },
+            };
+        }
+
+        report_duplicate_blocks(&current, ctx);
+        let mut generated: usize = 0;
+        flatten(&current, &overrides, None, ctx, &mut generated)
+    }
+
+    fn first_token(data: &metadata::Metadata) -> Option<TokenTypes> {
+        match data.get_tokens() {
+            Some(tks) => tks.first().map(|tk| tk.get_type()),
+            None => None,
+        }
+    }
+
+    // a text made only of blanks doesn't count as content, so an extends
+    // can be preceded by empty lines
+    fn is_blank_text(data: &metadata::Metadata) -> bool {
+        match data.get_tokens() {
+            Some(tks) => {
+                tks.len() == 1 &&
+                tks[0].get_type() == TokenTypes::TEXT &&
+                tks[0].get_data().trim().is_empty()
+            },
+            None => true,
+        }
+    }
+
+    // find_extends
+    // {% extends "template" %}
+    //
+    // Returns the parent template and the extends statement naming it, when
+    // extends is the first statement in meta
+    fn find_extends(meta: &[metadata::Metadata], ctx: &mut context::Context) -> Option<(String, metadata::Metadata)> {
+        let data = meta.iter().find(|data| !is_blank_text(data))?;
+        if first_token(data) != Some(TokenTypes::EXTENDS) {
+            return None;
+        }
+
+        let tks = data.get_tokens().as_ref()?;
+        match tks.get(1) {
+            Some(tk) if tk.get_type() == TokenTypes::STRING => Some((tk.get_data(), data.clone())),
+            _ => {
+                locate(data, ctx);
+                ctx.errors_push(ErrorKind::Template, String::from("extends expects a template name"));
+                None
+            },
+        }
+    }
+
+    // block_name
+    // {% block name %}
+    //
+    // Returns the name of the block data opens, if it opens one
+    fn block_name(data: &metadata::Metadata) -> Option<String> {
+        if first_token(data) != Some(TokenTypes::BLOCK) {
+            return None;
+        }
+
+        match data.get_tokens().as_ref()?.get(1) {
+            Some(tk) if tk.get_type() == TokenTypes::IDENTIFIER => Some(tk.get_data()),
+            _ => Some(String::new()),
+        }
+    }
+
+    // every block name can be defined once in a template, nested blocks
+    // included
+    fn report_duplicate_blocks(meta: &[metadata::Metadata], ctx: &mut context::Context) {
+        let mut seen: Vec<String> = Vec::new();
+
+        for data in meta {
+            match block_name(data) {
+                Some(name) if !name.is_empty() && seen.contains(&name) => {
+                    locate(data, ctx);
+                    ctx.errors_push(ErrorKind::Template, format!("block {} defined twice", name));
+                },
+                Some(name) => seen.push(name),
+                None => (),
+            }
+        }
+    }
+
+    // records the body of every block defined in meta, nested ones included,
+    // as the most derived version known so far. A block defined twice keeps
+    // its first body.
+    fn collect_blocks(meta: &[metadata::Metadata],
+                      overrides: &mut HashMap<String, Vec<metadata::Metainfo>>) {
+        let mut seen: Vec<String> = Vec::new();
+
+        for (i, data) in meta.iter().enumerate() {
+            let name = match block_name(data) {
+                Some(name) if !seen.contains(&name) => name,
+                _ => continue,
+            };
+
+            let end = find_block_end(meta, i + 1, TokenTypes::BLOCK, TokenTypes::ENDBLOCK)
+                .unwrap_or(meta.len());
+            overrides.entry(name.clone()).or_default().push(meta[i + 1..end].to_vec());
+            seen.push(name);
+        }
+    }
+
+    // returns meta without its extends statement and its blocks
+    fn strip_blocks(meta: &[metadata::Metadata]) -> metadata::Metainfo {
+        let mut ret = Vec::new();
+        let mut i = 0;
+
+        while i < meta.len() {
+            if block_name(&meta[i]).is_some() {
+                i = find_block_end(meta, i + 1, TokenTypes::BLOCK, TokenTypes::ENDBLOCK)
+                    .unwrap_or(meta.len()) + 1;
+                continue;
+            }
+
+            if first_token(&meta[i]) != Some(TokenTypes::EXTENDS) {
+                ret.push(meta[i].clone());
+            }
+            i += 1;
+        }
+
+        ret
+    }
+
+    // flatten
+    //
+    // Copies meta replacing every block by its most derived body. When the
+    // body overrides a parent block, the parent version becomes a macro
+    // and super() in the body is renamed to call it.
+    fn flatten(meta: &[metadata::Metadata],
+               overrides: &HashMap<String, Vec<metadata::Metainfo>>,
+               parent: Option<&str>,
+               ctx: &mut context::Context,
+               generated: &mut usize) -> metadata::Metainfo {
+        let mut ret = Vec::new();
+        let mut i = 0;
+
+        while i < meta.len() {
+            let name = match block_name(&meta[i]) {
+                Some(name) => name,
+                None => {
+                    ret.push(rename_super(&meta[i], parent));
+                    i += 1;
+                    continue;
+                },
+            };
+
+            if name.is_empty() {
+                locate(&meta[i], ctx);
+                ctx.errors_push(ErrorKind::Syntax, String::from("block name expected"));
+            }
+
+            let end = match find_block_end(meta, i + 1, TokenTypes::BLOCK, TokenTypes::ENDBLOCK) {
+                Some(end) => end,
+                None => {
+                    locate(&meta[i], ctx);
+                    ctx.errors_push(ErrorKind::Syntax, format!("block {} without endblock", name));
+                    meta.len()
+                },
+            };
+
+            let mut chain: Vec<&[metadata::Metadata]> = match overrides.get(&name) {
+                Some(bodies) => bodies.iter().map(|body| body.as_slice()).collect(),
+                None => Vec::new(),
+            };
+            chain.push(&meta[i + 1..end]);
+
+            ret.extend(flatten_block(&name, &chain, 0, overrides, ctx, generated));
+            i = end + 1;
+        }
+
+        ret
+    }
+
+    fn flatten_block(name: &str,
+                     chain: &[&[metadata::Metadata]],
+                     level: usize,
+                     overrides: &HashMap<String, Vec<metadata::Metainfo>>,
+                     ctx: &mut context::Context,
+                     generated: &mut usize) -> metadata::Metainfo {
+        let mut parent: Option<String> = None;
+
+        if level + 1 < chain.len() {
+            let body = flatten_block(name, chain, level + 1, overrides, ctx, generated);
+
+            // ':' cannot be part of an identifier, so the generated name never
+            // clashes with a macro written by hand
+            *generated += 1;
+            let super_name = format!("super:{}:{}", name, generated);
+            ctx.macro_add(super_name.clone(), context::Macro { params: Vec::new(), body });
+            parent = Some(super_name);
+        }
+
+        flatten(chain[level], overrides, parent.as_deref(), ctx, generated)
+    }
+
+    fn rename_super(data: &metadata::Metadata, parent: Option<&str>) -> metadata::Metadata {
+        let (parent, tks) = match (parent, data.get_tokens()) {
+            (Some(parent), Some(tks)) => (parent, tks),
+            _ => return data.clone(),
+        };
+
+        let mut renamed = tks.clone();
+        for i in 0..renamed.len() {
+            let is_call = renamed.get(i + 1).map(|tk| tk.get_type()) == Some(TokenTypes::LPAREN);
+            if renamed[i].get_type() == TokenTypes::IDENTIFIER && renamed[i].get_data() == "super" && is_call {
+                renamed[i] = token::Token::new(TokenTypes::IDENTIFIER, Some(parent.to_string()));
+            }
+        }
+
+        data.with_tokens(renamed)
+    }
+
+    // execute
+    //
+    // Runs every statement in meta. The program counter lives in the context
+    // so statements owning a block, like macro, can move it past the block.
+    fn execute(meta: &metadata::Metainfo, ctx: &mut context::Context) {
+        let saved_pc = ctx.program_counter();
+        ctx.set_program_counter(0);
+
+        while let Some(data) = meta.get(ctx.program_counter()) {
+            locate(data, ctx);
+            if let Some(tks) = data.get_tokens() {
+                let mut it = metadata::Tokenator::new(tks);
+                parse_statement(&mut it, ctx, meta);
+            }
+            ctx.set_program_counter(ctx.program_counter() + 1);
+        }
+
+        ctx.set_program_counter(saved_pc);
+    }
+
+    // points the errors reported next at data
+    fn locate(data: &metadata::Metadata, ctx: &mut context::Context) {
+        ctx.set_position(data.get_span(), Some(data.get_source().clone()));
+    }
+
+    // execute_isolated
+    //
+    // Runs meta as a template of its own, like a macro body or an imported
+    // template: its blocks must be closed inside it and its output is
+    // returned instead of printed
+    fn execute_isolated(meta: &metadata::Metainfo, ctx: &mut context::Context) -> String {
+        let (saved_span, saved_source) = ctx.position();
+        let branches = ctx.branches_take();
+        ctx.capture_begin();
+
+        execute(meta, ctx);
+        close_branches(ctx);
+
+        let output = ctx.capture_end();
+        ctx.branches_restore(branches);
+        ctx.set_position(saved_span, saved_source);
+        output
+    }
+
+    fn parse_statement<I>(iter: &mut I, ctx: &mut context::Context, meta: &metadata::Metainfo)
+    where I: TokenatorTrait {
+        if let Some(tk) = iter.next() {
+            match tk.get_type() {
+                TokenTypes::TEXT     => parse_text(iter, ctx),
+                TokenTypes::INVALID  => {
+                    ctx.errors_push(ErrorKind::Syntax, tk.get_data());
+                },
+                TokenTypes::PRINT    => parse_print(iter, ctx),
+                TokenTypes::IF       => parse_if(iter, ctx),
+                TokenTypes::ELIF     => parse_elif(iter, ctx),
+                TokenTypes::ELSE     => parse_else(ctx),
+                TokenTypes::ENDIF    => parse_endif(ctx),
+                TokenTypes::MACRO    => parse_macro(iter, ctx, meta),
+                TokenTypes::ENDMACRO => ctx.errors_push(ErrorKind::Syntax, String::from("mismatch endmacro")),
+                TokenTypes::IMPORT   => parse_import(iter, ctx),
+                TokenTypes::EXTENDS  => ctx.errors_push(ErrorKind::Template, String::from("extends must be the first statement")),
+                TokenTypes::ENDBLOCK => ctx.errors_push(ErrorKind::Syntax, String::from("mismatch endblock")),
+                TokenTypes::WITH     => parse_with(iter, ctx),
+                TokenTypes::ENDWITH  => parse_endwith(ctx),
+                TokenTypes::SET      => parse_set(iter, ctx),
+                TokenTypes::WHILE    => parse_while(iter, ctx),
+                TokenTypes::ENDWHILE => parse_endwhile(ctx, meta),
+                TokenTypes::BREAK    => parse_break(ctx, true),
+                TokenTypes::CONTINUE => parse_break(ctx, false),
+                TokenTypes::MATCH    => parse_match(iter, ctx),
+                TokenTypes::CASE     => parse_case(iter, ctx),
+                TokenTypes::DEFAULT  => parse_default(ctx),
+                TokenTypes::ENDMATCH => parse_endmatch(ctx),
+                TokenTypes::AUTOESCAPE    => parse_autoescape(iter, ctx),
+                TokenTypes::ENDAUTOESCAPE => parse_endautoescape(ctx),
+                _ => ctx.errors_push(ErrorKind::Syntax, format!("unknown statement {}", tk.get_data())),
+            }
+        }
+    }
 
-        for tokens in meta {
-            match tokens.get_tokens() {
-                Some(tks) => {
-                    let mut it = metadata::Tokenator::new(tks);
-                    parse_statement(&mut it, &mut ctx);
+    // find_block_end
+    //
+    // Returns the index of the metadata closing the block that starts right
+    // before start, blocks of the same kind nested in it are skipped
+    fn find_block_end(meta: &[metadata::Metadata], start: usize,
+                      open: TokenTypes, close: TokenTypes) -> Option<usize> {
+        let mut depth: usize = 0;
+
+        for (i, data) in meta.iter().enumerate().skip(start) {
+            let first = match data.get_tokens() {
+                Some(tks) => match tks.first() {
+                    Some(tk) => tk.get_type(),
+                    None => continue,
                 },
                 None => continue,
+            };
+
+            if first == open {
+                depth += 1;
+            }
+            else if first == close {
+                if depth == 0 {
+                    return Some(i);
+                }
+                depth -= 1;
             }
         }
-        //println!("{:#?}", ctx);
+
+        None
     }
 
-    fn parse_statement<I>(iter: &mut I, ctx: &mut context::Context)
+    // parse_text
+    //
+    // Writes text found outside blocks as is
+    fn parse_text<I>(iter: &mut I, ctx: &mut context::Context)
     where I: TokenatorTrait {
-        match iter.next() {
-            Some(tk) => match tk.get_type() {
-                TokenTypes::PRINT   => parse_print(iter, ctx),
-                TokenTypes::IF      => parse_if(iter, ctx),
-                TokenTypes::ELIF    => parse_elif(iter, ctx),
-                TokenTypes::ELSE    => parse_else(iter, ctx),
-                TokenTypes::ENDIF   => parse_endif(ctx),
-                _ => println!("{:?}", tk),
-            },
-            None => (),
+        if !ctx.branch_is_taken() {
+            return;
+        }
+
+        if let Some(tk) = iter.look_back() {
+            ctx.write(&tk.get_data());
         }
     }
 
     // parse_print
-    // {= expression =} or {% print expression %}
+    // {= expression =}, {= expression:spec =} or {% print expression %}
     //
-    // Returns an evaluated expression
+    // Returns an evaluated expression, laid out following spec when there
+    // is one and escaped unless it's a safe text. A safe text laid out
+    // stays safe, the padding comes from the template.
     fn parse_print<I>(iter: &mut I, ctx: &mut context::Context)
     where I: TokenatorTrait {
-        if !ctx.branch_is_taken().1 {
+        if !ctx.branch_is_taken() {
             return;
         }
 
-        match iter.look() {
-            Some(tk) => {
-                if tk.get_type() == TokenTypes::IDENTIFIER {
-                    println!("print id:{}", tk.get_data());
-                }
-                else {
-                    parse_expression(iter, ctx);
-                    match ctx.stack_pop() {
-                        Some(d) => match d {
-                            StackType::Text(t) => println!("text: {}", t),
-                            StackType::Number(t) => println!("number: {}", t),
-                            StackType::Bool(t) => println!("bool: {}", t),
-                        },
-                        None => {
-                            println!("Errors:");
-                            for err in ctx.errors_it() {
-                                println!("\t{:?}", err);
-                            }
-                        }
-                    }
+        if iter.look().is_none() {
+            return;
+        }
+
+        parse_expression(iter, ctx);
+        if iter.match_next(TokenTypes::FORMAT) {
+            let spec = iter.look_back().unwrap().get_data();
+            if let Some(d) = ctx.stack_pop() {
+                match format::format_value(&d, &spec) {
+                    Ok(text) if matches!(d, Value::Safe(_)) => ctx.stack_push(Value::Safe(text)),
+                    Ok(text) => ctx.stack_push(Value::Text(text)),
+                    Err(e) => {
+                        ctx.errors_push(ErrorKind::Operation, e);
+                        ctx.stack_push(d);
+                    },
                 }
+            }
+        }
+
+        if iter.look().is_some() {
+            ctx.errors_push(ErrorKind::Syntax, format!("unexpected token {:#?} in print", iter.look()));
+            ctx.stack_pop();
+            return;
+        }
+
+        match ctx.stack_pop() {
+            Some(Value::Safe(text)) => ctx.write(&text),
+            Some(d) if ctx.escape() == Escape::Off => ctx.write(&d.to_string()),
+            Some(d) => {
+                let escaped = ctx.escape().apply(&d.to_string());
+                ctx.write(&escaped);
             },
             None => (),
         }
@@ -84,21 +469,17 @@ pub mod parsing {
     // elif and else are optional
     fn parse_if<I>(iter: &mut I, ctx: &mut context::Context)
     where I: TokenatorTrait {
-        // if this block is being parsed but the branch above it was not taken,
-        // ignore it and skip the next tokens
-        if !ctx.branch_is_taken().1 {
+        // if this block is nested in a branch that was not taken, none of its
+        // clauses can be taken either, but it still needs a frame so its
+        // elif, else and endif match it and not the enclosing block
+        if !ctx.branch_is_taken() {
             iter.skip_all();
+            ctx.branch_push(TokenTypes::IF, false, true);
             return;
         }
 
-        parse_expression(iter, ctx);
-        match ctx.stack_pop() {
-            Some(d) => match d {
-                StackType::Bool(b) => ctx.branch_push(TokenTypes::IF, b),
-                _ => ctx.errors_push(String::from("if expression must evaluates to boolean")),
-            },
-            None => ctx.errors_push(String::from("if expression cannot be evaluated")),
-        }
+        let taken = parse_condition(iter, ctx, "if");
+        ctx.branch_push(TokenTypes::IF, taken, taken);
     }
 
     // parse_elif
@@ -107,52 +488,60 @@ pub mod parsing {
     // {% endif %}
     fn parse_elif<I>(iter: &mut I, ctx: &mut context::Context)
     where I: TokenatorTrait {
-        let branch = ctx.branch_is_taken();
-
-        // make sure we don't have an 'elif' clause beginning a branch sequence
-        if branch.0 != TokenTypes::IF {
-            ctx.errors_push(String::from("mimatch elif"));
-            return;
-        }
+        let line = ctx.line();
+        let decided = match ctx.branch_top() {
+            Some(b) if b.kind == TokenTypes::IF && !b.in_else => b.decided,
+            Some(b) if b.kind == TokenTypes::IF => {
+                let err = format!("elif at line {} after else of the if started at line {}", line, b.span.line);
+                ctx.errors_push(ErrorKind::Syntax, err);
+                iter.skip_all();
+                return;
+            },
+            top => {
+                let err = mismatch_message("elif", line, top);
+                ctx.errors_push(ErrorKind::Syntax, err);
+                iter.skip_all();
+                return;
+            },
+        };
 
-        // if the last branch was already taken we can skip this one
-        if branch.1 {
+        // if a previous clause was already taken we can skip this one
+        if decided {
             iter.skip_all();
+            if let Some(b) = ctx.branch_top() {
+                b.taken = false;
+            }
             return;
         }
 
-        if ctx.branch_pop().is_err() {
-            ctx.errors_push(String::from("unexpected elif error"));
-            return;
+        let taken = parse_condition(iter, ctx, "elif");
+        if let Some(b) = ctx.branch_top() {
+            b.taken = taken;
+            b.decided = taken;
         }
-        parse_if(iter, ctx);
     }
 
     // parse_else
     // {% else %}
     // body
     // {% endif %}
-    fn parse_else<I>(iter: &mut I, ctx: &mut context::Context)
-    where I: TokenatorTrait {
-        let branch = ctx.branch_is_taken();
-
-        // make sure 'else' matches an 'if' clause
-        if branch.0 != TokenTypes::IF {
-            ctx.errors_push(String::from("mismatch else"));
-            return;
-        }
-
-        // if the last branch was already taken we can skip this one
-        if branch.1 {
-            iter.skip_all();
-            return;
-        }
-
-        if ctx.branch_pop().is_err() {
-            ctx.errors_push(String::from("unexpected else error"));
-            return;
+    fn parse_else(ctx: &mut context::Context) {
+        let line = ctx.line();
+        match ctx.branch_top() {
+            Some(b) if b.kind == TokenTypes::IF && !b.in_else => {
+                b.taken = !b.decided;
+                b.decided = true;
+                b.in_else = true;
+            },
+            Some(b) if b.kind == TokenTypes::IF => {
+                let err = format!("else at line {} after else of the if started at line {}", line, b.span.line);
+                ctx.errors_push(ErrorKind::Syntax, err);
+            },
+            top => {
+                let err = mismatch_message("else", line, top);
+                ctx.errors_push(ErrorKind::Syntax, err);
+            },
         }
-        ctx.branch_push(TokenTypes::ELSE, true);
     }
 
     // parse_endif
@@ -160,354 +549,2750 @@ pub mod parsing {
     //
     // closes an if block
     fn parse_endif(ctx: &mut context::Context) {
-        let branch = ctx.branch_is_taken();
-
-        // make sure that this 'endif' really ends an 'if' clause
-        if branch.0 != TokenTypes::IF && branch.0 != TokenTypes::ELSE {
-            ctx.errors_push(String::from("mismatch endif"));
-            return;
+        let line = ctx.line();
+        match ctx.branch_top() {
+            Some(b) if b.kind == TokenTypes::IF => {
+                ctx.branch_pop();
+            },
+            top => {
+                let err = mismatch_message("endif", line, top);
+                ctx.errors_push(ErrorKind::Syntax, err);
+            },
         }
+    }
 
-        if ctx.branch_pop().is_err() {
-            ctx.errors_push(String::from("unexpected else error"));
+    // parse_with
+    // {% with name = expression, name = expression, ... %}
+    //   body
+    // {% endwith %}
+    //
+    // Binds each name in a new scope that shadows outer variables and is
+    // dropped by endwith. A binding can use the ones before it.
+    fn parse_with<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        if !ctx.branch_is_taken() {
+            iter.skip_all();
+            ctx.branch_push(TokenTypes::WITH, false, true);
             return;
         }
-    }
 
-    fn parse_expression<I>(iter: &mut I, ctx: &mut context::Context)
-    where I: TokenatorTrait {
-        parse_equality(iter, ctx)
-    }
+        ctx.scope_push(HashMap::new());
+        ctx.branch_push(TokenTypes::WITH, true, true);
+        if let Some(b) = ctx.branch_top() {
+            b.scope = true;
+        }
 
-    fn parse_equality<I>(iter: &mut I, ctx: &mut context::Context)
-    where I: TokenatorTrait {
-        parse_logical(iter, ctx);
+        loop {
+            if !iter.match_next(TokenTypes::IDENTIFIER) {
+                ctx.errors_push(ErrorKind::Syntax, String::from("with expects name = expression"));
+                return;
+            }
 
-        while iter.match_next(TokenTypes::EQ) ||
-              iter.match_next(TokenTypes::NE) {
+            let name = iter.look_back().unwrap().get_data();
+            if !iter.match_next(TokenTypes::ASSIGN) {
+                ctx.errors_push(ErrorKind::Syntax, format!("missing = after {} in with", name));
+                return;
+            }
 
-            let oper = iter.look_back().unwrap().get_type();
-            parse_logical(iter, ctx);
+            parse_expression(iter, ctx);
+            let data = ctx.stack_pop().unwrap_or(Value::Null);
+            if ctx.scope_set(&name, data).is_err() {
+                ctx.errors_push(ErrorKind::Syntax, String::from("unexpected with error"));
+                return;
+            }
 
-            let right = ctx.stack_pop().unwrap_or(context::StackType::Text(String::from("<null>")));
-            let left = ctx.stack_pop().unwrap_or(context::StackType::Text(String::from("<null>")));
-            match compute_binary(left, right, oper) {
-                Ok(stk) => ctx.stack_push(stk),
-                Err(e) => ctx.errors_push(e),
+            if !iter.match_next(TokenTypes::COMMA) {
+                break;
             }
         }
-    }
-
-    fn parse_logical<I>(iter: &mut I, ctx: &mut context::Context)
-    where I: TokenatorTrait {
-        parse_comparison(iter, ctx);
-
-        while iter.match_next(TokenTypes::AND) ||
-              iter.match_next(TokenTypes::OR) {
 
-            let oper = iter.look_back().unwrap().get_type();
-            parse_comparison(iter, ctx);
+        if iter.look().is_some() {
+            ctx.errors_push(ErrorKind::Syntax, format!("unexpected token {:#?} in with", iter.look()));
+        }
+    }
 
-            let right = ctx.stack_pop().unwrap_or(context::StackType::Text(String::from("<null>")));
-            let left = ctx.stack_pop().unwrap_or(context::StackType::Text(String::from("<null>")));
-            match compute_binary(left, right, oper) {
-                Ok(stk) => ctx.stack_push(stk),
-                Err(e)  => ctx.errors_push(e),
-            }
+    // parse_endwith
+    // {% endwith %}
+    //
+    // closes a with block, dropping its variables
+    fn parse_endwith(ctx: &mut context::Context) {
+        let line = ctx.line();
+        match ctx.branch_top() {
+            Some(b) if b.kind == TokenTypes::WITH => {
+                // a with nested in a branch not taken never opened a scope
+                if let Some(b) = ctx.branch_pop() {
+                    if b.scope {
+                        ctx.scope_pop();
+                    }
+                }
+            },
+            top => {
+                let err = mismatch_message("endwith", line, top);
+                ctx.errors_push(ErrorKind::Syntax, err);
+            },
         }
     }
 
-    fn parse_comparison<I>(iter: &mut I, ctx: &mut context::Context)
+    // parse_set
+    // {% set name = expression %}
+    //
+    // Gives name a new value where it's bound, so setting a variable of a
+    // with block changes it only until endwith. A name bound nowhere is
+    // bound in the innermost with block or macro call, or for the rest of
+    // the render when there's none. Inside a macro only the variables of the
+    // call can be set, and the host variables are never changed.
+    fn parse_set<I>(iter: &mut I, ctx: &mut context::Context)
     where I: TokenatorTrait {
-        parse_addition(iter, ctx);
+        if !ctx.branch_is_taken() {
+            iter.skip_all();
+            return;
+        }
 
-        while iter.match_next(TokenTypes::GT) ||
-              iter.match_next(TokenTypes::GE) ||
-              iter.match_next(TokenTypes::LT) ||
-              iter.match_next(TokenTypes::LE) {
+        if !iter.match_next(TokenTypes::IDENTIFIER) {
+            ctx.errors_push(ErrorKind::Syntax, String::from("set expects name = expression"));
+            return;
+        }
 
-            let oper = iter.look_back().unwrap().get_type();
-            parse_addition(iter, ctx);
+        let name = iter.look_back().unwrap().get_data();
+        if !iter.match_next(TokenTypes::ASSIGN) {
+            ctx.errors_push(ErrorKind::Syntax, format!("missing = after {} in set", name));
+            return;
+        }
 
-            let right = ctx.stack_pop().unwrap_or(context::StackType::Text(String::from("<null>")));
-            let left = ctx.stack_pop().unwrap_or(context::StackType::Text(String::from("<null>")));
-            match compute_binary(left, right, oper) {
-                Ok(stk) => ctx.stack_push(stk),
-                Err(e) => ctx.errors_push(e),
-            }
+        let errors = ctx.errors_count();
+        parse_expression(iter, ctx);
+        let data = ctx.stack_pop().unwrap_or(Value::Null);
+        if iter.look().is_some() {
+            ctx.errors_push(ErrorKind::Syntax, format!("unexpected token {:#?} in set", iter.look()));
+            return;
+        }
+
+        // an expression that failed leaves name as it was
+        if ctx.errors_count() == errors {
+            ctx.scope_assign(&name, data);
         }
     }
 
-    fn parse_addition<I>(iter: &mut I, ctx: &mut context::Context)
+    // parse_while
+    // {% while <identifier|primary> condition <identifier|primary> %}
+    //   body
+    // {% endwhile %}
+    //
+    // Runs body for as long as the condition evaluates to true, up to the
+    // maximum number of iterations set in Amps. The body changes what the
+    // condition sees with set, like a counter:
+    //
+    //   {% set i = 0 %}
+    //   {% while i lt 3 %}
+    //     {= i =}
+    //     {% set i = i + 1 %}
+    //   {% endwhile %}
+    fn parse_while<I>(iter: &mut I, ctx: &mut context::Context)
     where I: TokenatorTrait {
-        parse_multiplication(iter, ctx);
+        if !ctx.branch_is_taken() {
+            iter.skip_all();
+            ctx.branch_push(TokenTypes::WHILE, false, false);
+            return;
+        }
 
-        while iter.match_next(TokenTypes::PLUS) ||
-              iter.match_next(TokenTypes::MINUS) {
+        let taken = parse_condition(iter, ctx, "while");
+        ctx.branch_push(TokenTypes::WHILE, taken, taken);
+    }
 
-            let oper = iter.look_back().unwrap().get_type();
-            parse_multiplication(iter, ctx);
+    // parse_endwhile
+    // {% endwhile %}
+    //
+    // Evaluates the loop condition again and jumps back to the beginning of
+    // the body while it holds
+    fn parse_endwhile(ctx: &mut context::Context, meta: &metadata::Metainfo) {
+        let line = ctx.line();
+        let (running, start, iterations, started_at) = match ctx.branch_top() {
+            Some(b) if b.kind == TokenTypes::WHILE => (b.decided, b.start, b.iterations + 1, b.span.line),
+            top => {
+                let err = mismatch_message("endwhile", line, top);
+                ctx.errors_push(ErrorKind::Syntax, err);
+                return;
+            },
+        };
 
-            let right = ctx.stack_pop().unwrap_or(context::StackType::Text(String::from("<null>")));
-            let left = ctx.stack_pop().unwrap_or(context::StackType::Text(String::from("<null>")));
-            match compute_binary(left, right, oper) {
-                Ok(stk) => ctx.stack_push(stk),
-                Err(e) => ctx.errors_push(e),
-            }
+        let tokens = match meta.get(start).and_then(|data| data.get_tokens().as_ref()) {
+            Some(tks) if running => tks,
+            _ => {
+                ctx.branch_pop();
+                return;
+            },
+        };
+
+        let mut iter = metadata::Tokenator::new(tokens);
+        iter.next();
+        if !parse_condition(&mut iter, ctx, "while") {
+            ctx.branch_pop();
+            return;
         }
-    }
 
-    fn parse_multiplication<I>(iter: &mut I, ctx: &mut context::Context)
-    where I: TokenatorTrait {
-        parse_unary(iter, ctx);
+        let max = ctx.max_iterations();
+        if iterations >= max {
+            ctx.errors_push(ErrorKind::Limit, format!("while loop at line {} exceeded the maximum of {} iterations",
+                                    started_at, max));
+            ctx.branch_pop();
+            return;
+        }
 
-        while iter.match_next(TokenTypes::STAR) ||
-              iter.match_next(TokenTypes::PERCENT) ||
-              iter.match_next(TokenTypes::SLASH) {
+        if let Some(b) = ctx.branch_top() {
+            b.taken = true;
+            b.iterations = iterations;
+        }
+        ctx.set_program_counter(start);
+    }
 
-            let oper = iter.look_back().unwrap().get_type();
-            parse_unary(iter, ctx);
+    // parse_break
+    // {% break %} or {% continue %}
+    //
+    // Skips the rest of the innermost loop body, break also ends the loop
+    fn parse_break(ctx: &mut context::Context, stop: bool) {
+        if !ctx.branch_is_taken() {
+            return;
+        }
 
-            let right = ctx.stack_pop().unwrap_or(context::StackType::Text(String::from("<null>")));
-            let left = ctx.stack_pop().unwrap_or(context::StackType::Text(String::from("<null>")));
-            match compute_binary(left, right, oper) {
-                Ok(stk) => ctx.stack_push(stk),
-                Err(e) => ctx.errors_push(e),
-            }
+        if ctx.break_loop(stop).is_err() {
+            let statement = if stop { "break" } else { "continue" };
+            ctx.errors_push(ErrorKind::Syntax, format!("{} at line {} outside while", statement, ctx.line()));
+        }
+    }
+
+    // parse_match
+    // {% match expression %}
+    // {% case expression, expression, ... %}
+    //   body
+    // {% default %}
+    //   body
+    // {% endmatch %}
+    //
+    // Evaluates expression once and runs the body of the first case holding
+    // an equal value, or the body of default when none does. default is
+    // optional and must be the last clause.
+    fn parse_match<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        if !ctx.branch_is_taken() {
+            iter.skip_all();
+            ctx.branch_push(TokenTypes::MATCH, false, true);
+            return;
+        }
+
+        parse_expression(iter, ctx);
+        let subject = match ctx.stack_pop() {
+            Some(d) => d,
+            None => {
+                ctx.errors_push(ErrorKind::Operation, String::from("match expression cannot be evaluated"));
+                Value::Null
+            },
+        };
+
+        if iter.look().is_some() {
+            ctx.errors_push(ErrorKind::Syntax, format!("unexpected token {:#?} in match", iter.look()));
+        }
+
+        ctx.branch_push(TokenTypes::MATCH, false, false);
+        if let Some(b) = ctx.branch_top() {
+            b.subject = Some(subject);
+        }
+    }
+
+    // parse_case
+    // {% case expression, expression, ... %}
+    // body
+    // {% endmatch %}
+    //
+    // Every value is evaluated, even once a previous case was taken, so a
+    // value repeated from an earlier case is always reported
+    fn parse_case<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        let line = ctx.line();
+        let (subject, decided) = match ctx.branch_top() {
+            Some(b) if b.kind == TokenTypes::MATCH && !b.in_else => (b.subject.clone(), b.decided),
+            Some(b) if b.kind == TokenTypes::MATCH => {
+                let err = format!("case at line {} after default of the match started at line {}", line, b.span.line);
+                ctx.errors_push(ErrorKind::Syntax, err);
+                iter.skip_all();
+                return;
+            },
+            top => {
+                let err = mismatch_message("case", line, top);
+                ctx.errors_push(ErrorKind::Syntax, err);
+                iter.skip_all();
+                return;
+            },
+        };
+
+        // a match nested in a branch that was not taken has no subject
+        let subject = match subject {
+            Some(s) => s,
+            None => {
+                iter.skip_all();
+                return;
+            },
+        };
+
+        let mut values = Vec::new();
+        loop {
+            parse_expression(iter, ctx);
+            match ctx.stack_pop() {
+                Some(d) => values.push(d),
+                None => ctx.errors_push(ErrorKind::Operation, String::from("case expression cannot be evaluated")),
+            }
+
+            if !iter.match_next(TokenTypes::COMMA) {
+                break;
+            }
+        }
+
+        if iter.look().is_some() {
+            ctx.errors_push(ErrorKind::Syntax, format!("unexpected token {:#?} in case", iter.look()));
+        }
+
+        let mut taken = false;
+        for value in values {
+            let seen = match ctx.branch_top() {
+                Some(b) => b.cases.iter().find(|(v, _)| same_value(v, &value)).map(|(_, at)| *at),
+                None => None,
+            };
+
+            if let Some(at) = seen {
+                ctx.errors_push(ErrorKind::Operation, format!("duplicate case {} at line {}, already handled at line {}",
+                                        describe(&value), line, at));
+                continue;
+            }
+
+            taken = taken || same_value(&subject, &value);
+            if let Some(b) = ctx.branch_top() {
+                b.cases.push((value, line));
+            }
+        }
+
+        if let Some(b) = ctx.branch_top() {
+            b.taken = taken && !decided;
+            b.decided = decided || taken;
+        }
+    }
+
+    // parse_default
+    // {% default %}
+    // body
+    // {% endmatch %}
+    fn parse_default(ctx: &mut context::Context) {
+        let line = ctx.line();
+        match ctx.branch_top() {
+            Some(b) if b.kind == TokenTypes::MATCH && !b.in_else => {
+                b.taken = !b.decided;
+                b.decided = true;
+                b.in_else = true;
+            },
+            Some(b) if b.kind == TokenTypes::MATCH => {
+                let err = format!("default at line {} after default of the match started at line {}", line, b.span.line);
+                ctx.errors_push(ErrorKind::Syntax, err);
+            },
+            top => {
+                let err = mismatch_message("default", line, top);
+                ctx.errors_push(ErrorKind::Syntax, err);
+            },
+        }
+    }
+
+    // parse_endmatch
+    // {% endmatch %}
+    //
+    // closes a match block
+    fn parse_endmatch(ctx: &mut context::Context) {
+        let line = ctx.line();
+        match ctx.branch_top() {
+            Some(b) if b.kind == TokenTypes::MATCH => {
+                ctx.branch_pop();
+            },
+            top => {
+                let err = mismatch_message("endmatch", line, top);
+                ctx.errors_push(ErrorKind::Syntax, err);
+            },
+        }
+    }
+
+    // values of different types are never equal, so a case can't fail
+    // because its value can't be compared with the subject
+    fn same_value(a: &Value, b: &Value) -> bool {
+        matches!(compute_binary(a.clone(), b.clone(), TokenTypes::EQ), Ok(Value::Bool(true)))
+    }
+
+    // parse_autoescape
+    // {% autoescape name %}
+    //   body
+    // {% endautoescape %}
+    //
+    // Escapes the values echoed in body for the format called name: off,
+    // html, xml, json or shell
+    fn parse_autoescape<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        if !ctx.branch_is_taken() {
+            iter.skip_all();
+            ctx.branch_push(TokenTypes::AUTOESCAPE, false, true);
+            return;
+        }
+
+        ctx.branch_push(TokenTypes::AUTOESCAPE, true, true);
+
+        let escape = match iter.next() {
+            Some(tk) if tk.get_type() == TokenTypes::IDENTIFIER => {
+                let name = tk.get_data();
+                match Escape::from_name(&name) {
+                    Some(escape) => escape,
+                    None => {
+                        ctx.errors_push(ErrorKind::Syntax, format!("unknown autoescape {}, expected off, html, xml, json or shell", name));
+                        return;
+                    },
+                }
+            },
+            tk => {
+                ctx.errors_push(ErrorKind::Syntax, format!("autoescape expects off, html, xml, json or shell, found {:#?}", tk));
+                return;
+            },
+        };
+
+        let previous = ctx.escape();
+        ctx.set_escape(escape);
+        if let Some(b) = ctx.branch_top() {
+            b.escape = Some(previous);
+        }
+    }
+
+    // parse_endautoescape
+    // {% endautoescape %}
+    //
+    // closes an autoescape block, going back to the escape before it
+    fn parse_endautoescape(ctx: &mut context::Context) {
+        let line = ctx.line();
+        match ctx.branch_top() {
+            Some(b) if b.kind == TokenTypes::AUTOESCAPE => {
+                if let Some(escape) = ctx.branch_pop().and_then(|b| b.escape) {
+                    ctx.set_escape(escape);
+                }
+            },
+            top => {
+                let err = mismatch_message("endautoescape", line, top);
+                ctx.errors_push(ErrorKind::Syntax, err);
+            },
+        }
+    }
+
+    // parse_condition
+    //
+    // Evaluates the condition of statement. With strict truthiness anything
+    // but a boolean is reported and taken as false
+    fn parse_condition<I>(iter: &mut I, ctx: &mut context::Context, statement: &str) -> bool
+    where I: TokenatorTrait {
+        parse_expression(iter, ctx);
+        match ctx.stack_pop() {
+            Some(d) => match ctx.truth(&d) {
+                Some(b) => b,
+                None => {
+                    ctx.errors_push(ErrorKind::Operation, format!("{} expression must evaluates to boolean", statement));
+                    false
+                },
+            },
+            None => {
+                ctx.errors_push(ErrorKind::Operation, format!("{} expression cannot be evaluated", statement));
+                false
+            },
+        }
+    }
+
+    fn block_kind(kind: &TokenTypes) -> String {
+        format!("{:?}", kind).to_lowercase()
+    }
+
+    fn mismatch_message(statement: &str, line: usize, top: Option<&mut context::Branch>) -> String {
+        match top {
+            Some(b) => format!("mismatch {} at line {}, {} started at line {} is still open",
+                               statement, line, block_kind(&b.kind), b.span.line),
+            None => format!("mismatch {} at line {}, there is no open block", statement, line),
+        }
+    }
+
+    // reports every block left open at the end of a template, outermost first
+    fn close_branches(ctx: &mut context::Context) {
+        let mut unclosed = Vec::new();
+        while let Some(b) = ctx.branch_pop() {
+            if let Some(escape) = b.escape {
+                ctx.set_escape(escape);
+            }
+            // the scope of an unclosed with would be taken for the one of
+            // the macro running it
+            if b.scope {
+                ctx.scope_pop();
+            }
+            unclosed.push(b);
+        }
+
+        for b in unclosed.into_iter().rev() {
+            let err = format!("unclosed {} started at line {}", block_kind(&b.kind), b.span.line);
+            ctx.errors_push_at(ErrorKind::Syntax, err, b.span, b.source);
+        }
+    }
+
+    // parse_macro
+    // {% macro name(param, param=default, ...) %}
+    //   body
+    // {% endmacro %}
+    //
+    // Registers body to be rendered whenever name(...) is called, the body
+    // itself is skipped. Default values are evaluated here, once.
+    fn parse_macro<I>(iter: &mut I, ctx: &mut context::Context, meta: &metadata::Metainfo)
+    where I: TokenatorTrait {
+        let start = ctx.program_counter() + 1;
+        let end = match find_block_end(meta, start, TokenTypes::MACRO, TokenTypes::ENDMACRO) {
+            Some(end) => end,
+            None => {
+                ctx.errors_push(ErrorKind::Syntax, String::from("macro without endmacro"));
+                meta.len()
+            },
+        };
+        ctx.set_program_counter(end);
+
+        if !ctx.branch_is_taken() {
+            return;
+        }
+
+        if !iter.match_next(TokenTypes::IDENTIFIER) {
+            ctx.errors_push(ErrorKind::Syntax, String::from("macro name expected"));
+            return;
+        }
+
+        let name = iter.look_back().unwrap().get_data();
+        let mut params: Vec<(String, Option<Value>)> = Vec::new();
+
+        if iter.match_next(TokenTypes::LPAREN) {
+            while !iter.match_next(TokenTypes::RPAREN) {
+                if !iter.match_next(TokenTypes::IDENTIFIER) {
+                    ctx.errors_push(ErrorKind::Syntax, format!("invalid parameter in macro {}", name));
+                    return;
+                }
+
+                let param = iter.look_back().unwrap().get_data();
+                let default = if iter.match_next(TokenTypes::ASSIGN) {
+                    parse_expression(iter, ctx);
+                    ctx.stack_pop()
+                }
+                else {
+                    None
+                };
+                params.push((param, default));
+
+                if !iter.match_next(TokenTypes::COMMA) {
+                    if !iter.match_next(TokenTypes::RPAREN) {
+                        ctx.errors_push(ErrorKind::Syntax, format!("missing closing ) in macro {}", name));
+                        return;
+                    }
+                    break;
+                }
+            }
+        }
+
+        let body = meta[start..end].to_vec();
+        ctx.macro_add(name, context::Macro { params, body });
+    }
+
+    // parse_import
+    // {% import "template" %}
+    //
+    // Makes the macros defined in template available, anything else the
+    // template renders is discarded
+    fn parse_import<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        if !ctx.branch_is_taken() {
+            return;
+        }
+
+        if !iter.match_next(TokenTypes::STRING) {
+            ctx.errors_push(ErrorKind::Template, String::from("import expects a template name"));
+            return;
+        }
+
+        let name = iter.look_back().unwrap().get_data();
+        let source = match ctx.load_template(&name) {
+            Some(Ok(source)) => source,
+            Some(Err(e)) => {
+                ctx.errors_push(ErrorKind::Template, e);
+                return;
+            },
+            None => return,
+        };
+
+        let meta = compile(&scan::scanner::scan(&source, Some(&name)), ctx);
+        execute_isolated(&meta, ctx);
+    }
+
+    fn parse_expression<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        parse_nested(iter, ctx, parse_conditional)
+    }
+
+    // parse_nested
+    //
+    // Runs parse one level deeper, an expression nested too deep is
+    // reported and evaluates to null
+    fn parse_nested<I>(iter: &mut I, ctx: &mut context::Context, parse: fn(&mut I, &mut context::Context))
+    where I: TokenatorTrait {
+        if ctx.nesting() >= MAX_EXPRESSION_DEPTH {
+            ctx.too_deep(format!("expression nested more than {} levels deep", MAX_EXPRESSION_DEPTH));
+            iter.skip_all();
+            ctx.stack_push(Value::Null);
+            return;
+        }
+
+        ctx.nesting_push();
+        parse(iter, ctx);
+        ctx.nesting_pop();
+        ctx.too_deep_done();
+    }
+
+    // parse_conditional
+    // logical [if logical else conditional]
+    //
+    // The condition is evaluated first and only the selected branch is
+    // evaluated. The value before 'if' is not even parsed when the condition
+    // is false, the one after 'else' is parsed with evaluation suspended when
+    // it is true.
+    fn parse_conditional<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        let if_position = match find_inline_if(iter) {
+            Some(pos) => pos,
+            None => return parse_logical(iter, ctx),
+        };
+
+        let start = iter.position();
+        iter.seek(if_position + 1);
+        parse_logical(iter, ctx);
+
+        let taken = match ctx.stack_pop() {
+            Some(d) => match ctx.truth(&d) {
+                Some(b) => b,
+                None => {
+                    ctx.errors_push(ErrorKind::Operation, format!("inline if condition must evaluates to boolean, not {}", describe(&d)));
+                    false
+                },
+            },
+            None => false,
+        };
+
+        if !iter.match_next(TokenTypes::ELSE) {
+            ctx.errors_push(ErrorKind::Syntax, String::from("inline if without else"));
+            ctx.stack_push(Value::Null);
+            return;
+        }
+
+        if taken {
+            ctx.suspend_evaluation();
+            parse_conditional(iter, ctx);
+            ctx.stack_pop();
+            ctx.resume_evaluation();
+
+            let end = iter.position();
+            iter.seek(start);
+            parse_logical(iter, ctx);
+            iter.seek(end);
+        }
+        else {
+            parse_conditional(iter, ctx);
+        }
+    }
+
+    // looks for an 'if' that belongs to the expression starting at the current
+    // token, ignoring anything nested in (), [] or {} and stopping where the
+    // expression ends
+    fn find_inline_if<I>(iter: &I) -> Option<usize>
+    where I: TokenatorTrait {
+        let mut depth: usize = 0;
+        let mut offset: usize = 0;
+
+        while let Some(tk) = iter.look_nth(offset) {
+            match tk.get_type() {
+                TokenTypes::LPAREN | TokenTypes::LBRACKET | TokenTypes::LBRACE => depth += 1,
+                TokenTypes::RPAREN | TokenTypes::RBRACKET | TokenTypes::RBRACE => {
+                    if depth == 0 {
+                        return None;
+                    }
+                    depth -= 1;
+                },
+                TokenTypes::COMMA | TokenTypes::COLON | TokenTypes::ELSE if depth == 0 => return None,
+                TokenTypes::IF if depth == 0 => return Some(iter.position() + offset),
+                _ => (),
+            }
+            offset += 1;
+        }
+
+        None
+    }
+
+    // parse_logical
+    // comparison [and|or comparison]
+    //
+    // The right operand is parsed but not evaluated when the left one
+    // decides: false and ..., true or ...
+    fn parse_logical<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        parse_comparison(iter, ctx);
+
+        while iter.match_next(TokenTypes::AND) ||
+              iter.match_next(TokenTypes::OR) {
+
+            let oper = iter.look_back().unwrap().get_type();
+
+            // with lenient truthiness any value can be an operand
+            let left = ctx.stack_pop().unwrap_or(Value::Null);
+            let left = ctx.truth(&left).map_or(left, Value::Bool);
+            if left == Value::Bool(oper == TokenTypes::OR) {
+                ctx.suspend_evaluation();
+                parse_comparison(iter, ctx);
+                ctx.stack_pop();
+                ctx.resume_evaluation();
+                ctx.stack_push(left);
+                continue;
+            }
+
+            parse_comparison(iter, ctx);
+            let right = ctx.stack_pop().unwrap_or(Value::Null);
+            let right = ctx.truth(&right).map_or(right, Value::Bool);
+            match compute(left, right, oper, ctx) {
+                Ok(stk) => ctx.stack_push(stk),
+                Err(e)  => ctx.errors_push(ErrorKind::Operation, e),
+            }
+        }
+    }
+
+    // parse_comparison
+    // test [eq|ne|gt|ge|lt|le|in|not in|matches|not matches] test ...
+    //
+    // 'x not in y' is evaluated as 'x in y' and negated, the same goes for
+    // 'not matches'. Comparisons chain like in math, '1 le x le 10' is
+    // '1 le x and x le 10' with x evaluated only once. Once a comparison is
+    // false the operands after it are parsed but not evaluated.
+    fn parse_comparison<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        parse_test(iter, ctx);
+
+        let mut left: Option<Value> = None;
+        let mut result = Value::Bool(true);
+
+        loop {
+            let negate = match_not_in(iter);
+            if !iter.match_next(TokenTypes::EQ) &&
+               !iter.match_next(TokenTypes::NE) &&
+               !iter.match_next(TokenTypes::GT) &&
+               !iter.match_next(TokenTypes::GE) &&
+               !iter.match_next(TokenTypes::LT) &&
+               !iter.match_next(TokenTypes::LE) &&
+               !iter.match_next(TokenTypes::IN) &&
+               !iter.match_next(TokenTypes::MATCHES) {
+                break;
+            }
+
+            let oper = iter.look_back().unwrap().get_type();
+            let current = match left.take() {
+                Some(d) => d,
+                None => ctx.stack_pop().unwrap_or(Value::Null),
+            };
+
+            let decided = result != Value::Bool(true);
+            if decided {
+                ctx.suspend_evaluation();
+            }
+            parse_test(iter, ctx);
+            if decided {
+                ctx.resume_evaluation();
+            }
+
+            let right = ctx.stack_pop().unwrap_or(Value::Null);
+            if decided || ctx.is_suspended() {
+                left = Some(right);
+                continue;
+            }
+
+            let computed = if oper == TokenTypes::MATCHES {
+                regex_matches(current, right.clone(), ctx)
+            }
+            else {
+                compute(current, right.clone(), oper, ctx)
+            };
+
+            result = match computed {
+                Ok(Value::Bool(b)) => Value::Bool(b != negate),
+                Ok(d) => d,
+                Err(e) => {
+                    ctx.errors_push(ErrorKind::Operation, e);
+                    Value::Null
+                },
+            };
+            left = Some(right);
+        }
+
+        if left.is_some() {
+            ctx.stack_push(result);
+        }
+    }
+
+    // consumes 'not' when 'in' or 'matches' comes next, leaving the operator
+    // to be matched. A lone 'not' is left for the unary operator
+    fn match_not_in<I>(iter: &mut I) -> bool
+    where I: TokenatorTrait {
+        let found = match (iter.look(), iter.look_ahead()) {
+            (Some(a), Some(b)) => a.get_type() == TokenTypes::NOT &&
+                                  (b.get_type() == TokenTypes::IN || b.get_type() == TokenTypes::MATCHES),
+            _ => false,
+        };
+
+        if found {
+            iter.next();
+        }
+        found
+    }
+
+    // text matches pattern, true when the pattern is found anywhere in text
+    fn regex_matches(left: Value, right: Value, ctx: &mut context::Context) -> Result<Value, String> {
+        match (left.plain(), right.plain()) {
+            (Value::Text(text), Value::Text(pattern)) => {
+                let re = ctx.regex(&pattern)?;
+                Ok(Value::Bool(re.is_match(&text)))
+            },
+            (l, r) => Err(format!("mismatch types {} matches {}", describe(&l), describe(&r))),
+        }
+    }
+
+    // parse_test
+    // bitwise_or [is [not] name]
+    //
+    // Applies the test called name to the value, tests added by the host
+    // come first. defined and undefined tell whether the value could be
+    // evaluated, so the errors it raised are dropped.
+    fn parse_test<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        let errors = ctx.errors_count();
+        parse_bitwise_or(iter, ctx);
+
+        while iter.match_next(TokenTypes::IS) {
+            let negate = iter.match_next(TokenTypes::NOT);
+            let name = match iter.next() {
+                Some(tk) if tk.get_type() == TokenTypes::IDENTIFIER ||
+                            tk.get_type() == TokenTypes::BOOLEAN ||
+                            tk.get_type() == TokenTypes::NIL => tk.get_data(),
+                tk => {
+                    ctx.errors_push(ErrorKind::Syntax, format!("is expects a test name, found {:#?}", tk));
+                    ctx.stack_pop();
+                    ctx.stack_push(Value::Null);
+                    return;
+                },
+            };
+
+            let data = ctx.stack_pop().unwrap_or(Value::Null).plain();
+
+            // tests added by the host may do anything, they don't run on
+            // operands that are not evaluated
+            if ctx.is_suspended() {
+                ctx.stack_push(Value::Null);
+                continue;
+            }
+
+            let result = match ctx.run_test(&name, &data) {
+                Some(b) => Ok(b),
+                None if name == "defined" || name == "undefined" => {
+                    let defined = ctx.errors_count() == errors;
+                    ctx.errors_truncate(errors);
+                    Ok(defined == (name == "defined"))
+                },
+                None => builtin_test(&name, &data),
+            };
+
+            match result {
+                Ok(b) => ctx.stack_push(Value::Bool(b != negate)),
+                Err(e) => {
+                    ctx.errors_push(ErrorKind::Operation, e);
+                    ctx.stack_push(Value::Null);
+                },
+            }
+        }
+    }
+
+    fn builtin_test(name: &str, data: &Value) -> Result<bool, String> {
+        match (name, data) {
+            ("empty", Value::Null)       => Ok(true),
+            ("empty", Value::Text(t))    => Ok(t.is_empty()),
+            ("empty", Value::List(l))    => Ok(l.is_empty()),
+            ("empty", Value::Map(m))     => Ok(m.iter().next().is_none()),
+            ("even", Value::Int(n))      => Ok(n % 2 == 0),
+            ("odd", Value::Int(n))       => Ok(n % 2 != 0),
+            ("number", _)                => Ok(matches!(data, Value::Int(_) | Value::Float(_))),
+            ("integer", _)               => Ok(matches!(data, Value::Int(_))),
+            ("float", _)                 => Ok(matches!(data, Value::Float(_))),
+            ("string", _)                => Ok(matches!(data, Value::Text(_))),
+            ("boolean", _)               => Ok(matches!(data, Value::Bool(_))),
+            ("list", _)                  => Ok(matches!(data, Value::List(_))),
+            ("map", _)                   => Ok(matches!(data, Value::Map(_))),
+            ("null", _)                  => Ok(matches!(data, Value::Null)),
+            ("empty", _) | ("even", _) | ("odd", _) => {
+                Err(format!("test {} is invalid for {}", name, describe(data)))
+            },
+            _ => Err(format!("unknown test {}", name)),
+        }
+    }
+
+    // parse_bitwise_or
+    // bitwise_xor [| bitwise_xor]
+    fn parse_bitwise_or<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        parse_bitwise_xor(iter, ctx);
+
+        while iter.match_next(TokenTypes::PIPE) {
+
+            let oper = iter.look_back().unwrap().get_type();
+            parse_bitwise_xor(iter, ctx);
+
+            let right = ctx.stack_pop().unwrap_or(Value::Null);
+            let left = ctx.stack_pop().unwrap_or(Value::Null);
+            match compute(left, right, oper, ctx) {
+                Ok(stk) => ctx.stack_push(stk),
+                Err(e) => ctx.errors_push(ErrorKind::Operation, e),
+            }
+        }
+    }
+
+    // parse_bitwise_xor
+    // bitwise_and [^ bitwise_and]
+    fn parse_bitwise_xor<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        parse_bitwise_and(iter, ctx);
+
+        while iter.match_next(TokenTypes::CARET) {
+
+            let oper = iter.look_back().unwrap().get_type();
+            parse_bitwise_and(iter, ctx);
+
+            let right = ctx.stack_pop().unwrap_or(Value::Null);
+            let left = ctx.stack_pop().unwrap_or(Value::Null);
+            match compute(left, right, oper, ctx) {
+                Ok(stk) => ctx.stack_push(stk),
+                Err(e) => ctx.errors_push(ErrorKind::Operation, e),
+            }
+        }
+    }
+
+    // parse_bitwise_and
+    // shift [& shift]
+    fn parse_bitwise_and<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        parse_shift(iter, ctx);
+
+        while iter.match_next(TokenTypes::AMPERSAND) {
+
+            let oper = iter.look_back().unwrap().get_type();
+            parse_shift(iter, ctx);
+
+            let right = ctx.stack_pop().unwrap_or(Value::Null);
+            let left = ctx.stack_pop().unwrap_or(Value::Null);
+            match compute(left, right, oper, ctx) {
+                Ok(stk) => ctx.stack_push(stk),
+                Err(e) => ctx.errors_push(ErrorKind::Operation, e),
+            }
+        }
+    }
+
+    // parse_shift
+    // addition [<<|>> addition]
+    fn parse_shift<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        parse_addition(iter, ctx);
+
+        while iter.match_next(TokenTypes::LSHIFT) ||
+              iter.match_next(TokenTypes::RSHIFT) {
+
+            let oper = iter.look_back().unwrap().get_type();
+            parse_addition(iter, ctx);
+
+            let right = ctx.stack_pop().unwrap_or(Value::Null);
+            let left = ctx.stack_pop().unwrap_or(Value::Null);
+            match compute(left, right, oper, ctx) {
+                Ok(stk) => ctx.stack_push(stk),
+                Err(e) => ctx.errors_push(ErrorKind::Operation, e),
+            }
+        }
+    }
+
+    fn parse_addition<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        parse_multiplication(iter, ctx);
+
+        while iter.match_next(TokenTypes::PLUS) ||
+              iter.match_next(TokenTypes::MINUS) {
+
+            let oper = iter.look_back().unwrap().get_type();
+            parse_multiplication(iter, ctx);
+
+            let right = ctx.stack_pop().unwrap_or(Value::Null);
+            let left = ctx.stack_pop().unwrap_or(Value::Null);
+            match compute(left, right, oper, ctx) {
+                Ok(stk) => ctx.stack_push(stk),
+                Err(e) => ctx.errors_push(ErrorKind::Operation, e),
+            }
+        }
+    }
+
+    fn parse_multiplication<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        parse_unary(iter, ctx);
+
+        while iter.match_next(TokenTypes::STAR) ||
+              iter.match_next(TokenTypes::PERCENT) ||
+              iter.match_next(TokenTypes::SLASH) ||
+              iter.match_next(TokenTypes::DOUBLESLASH) {
+
+            let oper = iter.look_back().unwrap().get_type();
+            parse_unary(iter, ctx);
+
+            let right = ctx.stack_pop().unwrap_or(Value::Null);
+            let left = ctx.stack_pop().unwrap_or(Value::Null);
+            match compute(left, right, oper, ctx) {
+                Ok(stk) => ctx.stack_push(stk),
+                Err(e) => ctx.errors_push(ErrorKind::Operation, e),
+            }
         }
     }
 
     fn parse_unary<I>(iter: &mut I, ctx: &mut context::Context)
     where I: TokenatorTrait {
         if iter.match_next(TokenTypes::MINUS) ||
+           iter.match_next(TokenTypes::TILDE) ||
            iter.match_next(TokenTypes::NOT) {
 
             let operator = iter.look_back().unwrap().get_type();
 
             // handle sequence of unary operators like recursively
             // !!!!var or ---3
-            parse_unary(iter, ctx);
+            parse_nested(iter, ctx, parse_unary);
 
             let last_eval = ctx.stack_pop();
             if operator == TokenTypes::MINUS {
                 match last_eval {
                     Some(d) => match d {
-                        StackType::Text(t)   => ctx.errors_push(String::from(format!("invalid -{}", t))),
-                        StackType::Bool(b)   => ctx.errors_push(String::from(format!("invalid -{}", b))),
-                        StackType::Number(n) => ctx.stack_push(StackType::Number(-n)),
+                        Value::Int(n) => match n.checked_neg() {
+                            Some(i) => ctx.stack_push(Value::Int(i)),
+                            None    => {
+                                ctx.errors_push(ErrorKind::Operation, format!("-{} overflows", n));
+                                ctx.stack_push(Value::Null);
+                            },
+                        },
+                        Value::Float(n) => ctx.stack_push(Value::Float(-n)),
+                        _ => {
+                            ctx.errors_push(ErrorKind::Operation, format!("invalid -{}", describe(&d)));
+                            ctx.stack_push(Value::Null);
+                        },
                     },
                     None => {
-                        ctx.errors_push(String::from(format!("no data retrieved from the stack")));
+                        ctx.errors_push(ErrorKind::Syntax, String::from("no data retrieved from the stack"));
+                    }
+                }
+            }
+            else if operator == TokenTypes::TILDE {
+                match last_eval {
+                    Some(Value::Int(n)) => ctx.stack_push(Value::Int(!n)),
+                    Some(d) => {
+                        ctx.errors_push(ErrorKind::Operation, format!("invalid ~{}", describe(&d)));
+                        ctx.stack_push(Value::Null);
+                    },
+                    None => {
+                        ctx.errors_push(ErrorKind::Syntax, String::from("no data retrieved from the stack"));
                     }
                 }
             }
             else {
                 match last_eval {
-                    Some(d) => match d {
-                        StackType::Text(t)   => ctx.errors_push(String::from(format!("invalid !{}", t))),
-                        StackType::Bool(b)   => ctx.stack_push(StackType::Bool(!b)),
-                        StackType::Number(n) => ctx.stack_push(StackType::Number(!n)),
+                    // not is always logical, ~ is the bitwise not of an int
+                    Some(d) => match ctx.truth(&d) {
+                        Some(b) => ctx.stack_push(Value::Bool(!b)),
+                        None => {
+                            ctx.errors_push(ErrorKind::Operation, format!("invalid not {}", describe(&d)));
+                            ctx.stack_push(Value::Null);
+                        },
                     },
                     None => {
-                        ctx.errors_push(String::from(format!("no data retrieved from the stack")));
+                        ctx.errors_push(ErrorKind::Syntax, String::from("no data retrieved from the stack"));
                     }
                 }
             }
         }
-        else {
-            parse_primary(iter, ctx);
-        }
+        else {
+            parse_power(iter, ctx);
+        }
+    }
+
+    // parse_power
+    // primary [** unary]
+    //
+    // ** is right associative and binds tighter than an unary operator on
+    // its left, so -2 ** 2 is -(2 ** 2) and 2 ** -1 is valid
+    fn parse_power<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        parse_primary(iter, ctx);
+
+        if iter.match_next(TokenTypes::DOUBLESTAR) {
+            parse_nested(iter, ctx, parse_unary);
+
+            let right = ctx.stack_pop().unwrap_or(Value::Null);
+            let left = ctx.stack_pop().unwrap_or(Value::Null);
+            match compute(left, right, TokenTypes::DOUBLESTAR, ctx) {
+                Ok(stk) => ctx.stack_push(stk),
+                Err(e) => ctx.errors_push(ErrorKind::Operation, e),
+            }
+        }
+    }
+
+    fn parse_primary<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        if iter.match_next(TokenTypes::NUMBER) {
+            let data = iter.look_back().unwrap().get_data();
+            if data.contains('.') {
+                match data.parse::<f64>() {
+                    Ok(n) => ctx.stack_push(Value::Float(n)),
+                    Err(e) => {
+                        ctx.errors_push(ErrorKind::Operation, format!("impossible converting {} to f64 - {}", data, e));
+                        ctx.stack_push(Value::Null);
+                    },
+                }
+            }
+            else {
+                match data.parse::<i64>() {
+                    Ok(n) => ctx.stack_push(Value::Int(n)),
+                    Err(e) => {
+                        ctx.errors_push(ErrorKind::Operation, format!("impossible converting {} to i64 - {}", data, e));
+                        ctx.stack_push(Value::Null);
+                    },
+                }
+            }
+        }
+        else if iter.match_next(TokenTypes::STRING) {
+            let data = iter.look_back().unwrap().get_data();
+            ctx.stack_push(Value::Text(data.clone()));
+            parse_subscript(iter, ctx, format!("{:?}", data));
+        }
+        else if iter.match_next(TokenTypes::INTERPOLATION) {
+            let data = iter.look_back().unwrap().get_data();
+            let text = interpolate(&data, ctx);
+            ctx.stack_push(Value::Text(text));
+            parse_subscript(iter, ctx, format!("{:?}", data));
+        }
+        else if iter.match_next(TokenTypes::TRUE) {
+            ctx.stack_push(Value::Bool(true));
+        }
+        else if iter.match_next(TokenTypes::FALSE) {
+            ctx.stack_push(Value::Bool(false));
+        }
+        else if iter.match_next(TokenTypes::NIL) {
+            ctx.stack_push(Value::Null);
+        }
+        else if iter.match_next(TokenTypes::LPAREN) {
+            parse_expression(iter, ctx);
+
+            if !iter.match_next(TokenTypes::RPAREN) {
+                ctx.errors_push(ErrorKind::Syntax, String::from("missing closing )"));
+            }
+            parse_subscript(iter, ctx, String::from("(...)"));
+        }
+        else if iter.match_next(TokenTypes::LBRACKET) {
+            parse_list(iter, ctx);
+            parse_subscript(iter, ctx, String::from("[...]"));
+        }
+        else if iter.match_next(TokenTypes::LBRACE) {
+            parse_map(iter, ctx);
+            parse_subscript(iter, ctx, String::from("{...}"));
+        }
+        else if iter.match_next(TokenTypes::IDENTIFIER) {
+            let key = iter.look_back().unwrap().get_data();
+            if iter.match_next(TokenTypes::LPAREN) {
+                let args = parse_arguments(iter, ctx);
+                call_function(&key, args, ctx);
+            }
+            else {
+                parse_variable(iter, ctx, key);
+                return;
+            }
+            parse_subscript(iter, ctx, key);
+        }
+        else {
+            // the operator expecting this operand still pops one
+            ctx.errors_push(ErrorKind::Syntax, format!("unexpected token {:#?}", iter.look()));
+            ctx.stack_push(Value::Null);
+        }
+    }
+
+    // interpolate
+    // "text ${expression} text"
+    //
+    // Returns data with every ${expression} replaced by the evaluated
+    // expression, displayed the same way it would be printed. \${ is kept
+    // as a literal ${.
+    fn interpolate(data: &str, ctx: &mut context::Context) -> String {
+        let mut ret = String::new();
+        let mut rest = data;
+
+        while let Some(begin) = rest.find("${") {
+            // \${ is a literal ${
+            if rest[..begin].ends_with('\\') {
+                ret.push_str(&rest[..begin - 1]);
+                ret.push_str("${");
+                rest = &rest[begin + 2..];
+                continue;
+            }
+
+            ret.push_str(&rest[..begin]);
+            rest = &rest[begin + 2..];
+
+            // the expression ends at the } matching ${, so it can have map
+            // literals and strings of its own
+            let end = match scan::scanner::expression_end(rest) {
+                Some(end) => end,
+                None => {
+                    ctx.errors_push(ErrorKind::Syntax, format!("unclosed ${{ in \"{}\"", data));
+                    return ret;
+                },
+            };
+
+            let code = &rest[..end];
+            let tokens = match scan::scanner::tokenize_expression(code) {
+                Ok(tks) => tks,
+                Err(e) => {
+                    ctx.errors_push(ErrorKind::Syntax, format!("{} in ${{{}}}", e, code));
+                    rest = &rest[end + 1..];
+                    continue;
+                },
+            };
+            let mut it = metadata::Tokenator::new(&tokens);
+            if it.look().is_none() {
+                ctx.errors_push(ErrorKind::Syntax, format!("empty ${{}} in \"{}\"", data));
+            }
+            else {
+                parse_expression(&mut it, ctx);
+                if let Some(tk) = it.look() {
+                    ctx.errors_push(ErrorKind::Syntax, format!("unexpected token {:?} in ${{{}}}", tk.get_data(), code));
+                }
+                ret.push_str(&ctx.stack_pop().unwrap_or(Value::Null).to_string());
+            }
+
+            rest = &rest[end + 1..];
+        }
+
+        ret.push_str(rest);
+        ret
+    }
+
+    // parse_arguments
+    // (expression, expression, ...)
+    //
+    // Returns the evaluated arguments of a call, the opening ( is already
+    // consumed
+    fn parse_arguments<I>(iter: &mut I, ctx: &mut context::Context) -> Vec<Value>
+    where I: TokenatorTrait {
+        let mut args = Vec::new();
+
+        while !iter.match_next(TokenTypes::RPAREN) {
+            if iter.look().is_none() {
+                ctx.errors_push(ErrorKind::Syntax, String::from("missing closing )"));
+                break;
+            }
+
+            parse_expression(iter, ctx);
+            args.push(ctx.stack_pop().unwrap_or(Value::Null));
+
+            if !iter.match_next(TokenTypes::COMMA) {
+                if !iter.match_next(TokenTypes::RPAREN) {
+                    ctx.errors_push(ErrorKind::Syntax, String::from("missing closing )"));
+                }
+                break;
+            }
+        }
+
+        args
+    }
+
+    // call_function
+    // name(arguments)
+    //
+    // Pushes the result of calling name, a macro or a builtin function.
+    // Macros come first so a template can replace a builtin.
+    fn call_function(name: &str, args: Vec<Value>, ctx: &mut context::Context) {
+        // a call in an operand that is not evaluated does nothing
+        if ctx.is_suspended() {
+            ctx.stack_push(Value::Null);
+            return;
+        }
+
+        if let Some(mac) = ctx.macro_get(name) {
+            call_macro(name, &mac, args, ctx);
+            return;
+        }
+
+        // builtins take safe texts as plain ones, what they return is new
+        let args: Vec<Value> = args.into_iter().map(Value::plain).collect();
+        match call_builtin(name, &args, ctx) {
+            Some(Ok(d)) => ctx.stack_push(d),
+            Some(Err(e)) => {
+                ctx.errors_push(ErrorKind::Operation, format!("{}: {}", name, e));
+                ctx.stack_push(Value::Null);
+            },
+            None => {
+                ctx.errors_push(ErrorKind::Lookup, format!("unknown function {}", name));
+                ctx.stack_push(Value::Null);
+            },
+        }
+    }
+
+    // call_builtin
+    //
+    // Returns the result of the builtin function called name, None when
+    // there isn't one
+    //
+    // format(value, spec)
+    //   value laid out like {= value:spec =}
+    // regex_replace(text, pattern, replacement)
+    //   text with every match of pattern replaced, $1 in replacement is
+    //   the first group
+    // regex_captures(text, pattern)
+    //   list with the first match followed by each group, a group that
+    //   didn't take part is null. The list is empty if nothing matches
+    // int(value), float(value)
+    //   the number in a text, a float truncated toward zero, 1 or 0 for
+    //   a boolean
+    // str(value)
+    //   value as it would be printed
+    // bool(value)
+    //   true for the text "true", false for "false", any other value
+    //   follows the lenient truthiness
+    // safe(value)
+    //   value as text that is printed without being escaped
+    fn call_builtin(name: &str, args: &[Value], ctx: &mut context::Context) -> Option<Result<Value, String>> {
+        let ret = match (name, args) {
+            ("format", [d, Value::Text(spec)]) => format::format_value(d, spec).map(Value::Text),
+            ("format", [_, spec]) => Err(format!("spec must be text, not {}", spec.type_name())),
+            ("format", _) => Err(format!("expects 2 arguments, found {}", args.len())),
+
+            ("regex_replace", [Value::Text(text), Value::Text(pattern), Value::Text(repl)]) => {
+                ctx.regex(pattern).map(|re| Value::Text(re.replace_all(text, repl.as_str()).into_owned()))
+            },
+            ("regex_replace", [_, _, _]) => Err(String::from("expects text arguments")),
+            ("regex_replace", _) => Err(format!("expects 3 arguments, found {}", args.len())),
+
+            ("regex_captures", [Value::Text(text), Value::Text(pattern)]) => {
+                ctx.regex(pattern).map(|re| match re.captures(text) {
+                    Some(caps) => Value::List(caps.iter().map(|group| match group {
+                        Some(m) => Value::Text(m.as_str().to_string()),
+                        None => Value::Null,
+                    }).collect()),
+                    None => Value::List(Vec::new()),
+                })
+            },
+            ("regex_captures", [_, _]) => Err(String::from("expects text arguments")),
+            ("regex_captures", _) => Err(format!("expects 2 arguments, found {}", args.len())),
+
+            ("safe", [d]) => Ok(Value::Safe(d.to_string())),
+            ("safe", _) => Err(format!("expects 1 argument, found {}", args.len())),
+
+            ("int", [d]) => convert_int(d),
+            ("float", [d]) => convert_float(d),
+            ("str", [d]) => Ok(Value::Text(d.to_string())),
+            ("bool", [Value::Text(t)]) | ("bool", [Value::Safe(t)]) if t == "true" || t == "false" => {
+                Ok(Value::Bool(t == "true"))
+            },
+            ("bool", [d]) => Ok(Value::Bool(d.is_truthy())),
+            ("int", _) | ("float", _) | ("str", _) | ("bool", _) => {
+                Err(format!("expects 1 argument, found {}", args.len()))
+            },
+            _ => return None,
+        };
+
+        Some(ret)
+    }
+
+    // call_macro
+    //
+    // Renders the macro body with its parameters bound in a new scope and
+    // pushes the rendered text
+    fn call_macro(name: &str, mac: &context::Macro, args: Vec<Value>, ctx: &mut context::Context) {
+        if ctx.is_suspended() {
+            ctx.stack_push(Value::Null);
+            return;
+        }
+
+        if args.len() > mac.params.len() {
+            ctx.errors_push(ErrorKind::Lookup, format!("macro {} takes {} arguments but {} were given",
+                                    name, mac.params.len(), args.len()));
+            ctx.stack_push(Value::Null);
+            return;
+        }
+
+        let max = ctx.max_macro_depth();
+        if ctx.capture_depth() >= max {
+            ctx.errors_push(ErrorKind::Limit, format!("macro {} nested more than {} calls deep", name, max));
+            ctx.stack_push(Value::Null);
+            return;
+        }
+
+        let mut scope = HashMap::new();
+        let mut args = args.into_iter();
+        for (param, default) in &mac.params {
+            match args.next().or_else(|| default.clone()) {
+                Some(v) => {
+                    scope.insert(param.clone(), v);
+                },
+                None => {
+                    ctx.errors_push(ErrorKind::Lookup, format!("macro {} missing argument {}", name, param));
+                    ctx.stack_push(Value::Null);
+                    return;
+                },
+            }
+        }
+
+        if ctx.nesting() >= MAX_EXPRESSION_DEPTH {
+            ctx.too_deep(format!("expression nested more than {} levels deep", MAX_EXPRESSION_DEPTH));
+            ctx.stack_push(Value::Null);
+            return;
+        }
+
+        ctx.scope_push_call(scope);
+        ctx.nesting_push();
+        let output = execute_isolated(&mac.body, ctx);
+        ctx.nesting_pop();
+        ctx.scope_pop();
+        ctx.too_deep_done();
+
+        // values echoed in the body are escaped already
+        ctx.stack_push(Value::Safe(output));
+    }
+
+    fn convert_int(data: &Value) -> Result<Value, String> {
+        let fail = || format!("cannot convert {} to int", describe(data));
+        match data {
+            Value::Int(n) => Ok(Value::Int(*n)),
+            Value::Bool(b) => Ok(Value::Int(*b as i64)),
+            Value::Float(f) if f.is_finite() && *f >= i64::MIN as f64 && *f < i64::MAX as f64 => {
+                Ok(Value::Int(f.trunc() as i64))
+            },
+            Value::Text(t) | Value::Safe(t) => t.trim().parse::<i64>().map(Value::Int).map_err(|_| fail()),
+            _ => Err(fail()),
+        }
+    }
+
+    fn convert_float(data: &Value) -> Result<Value, String> {
+        let fail = || format!("cannot convert {} to float", describe(data));
+        match data {
+            Value::Int(n) => Ok(Value::Float(*n as f64)),
+            Value::Float(f) => Ok(Value::Float(*f)),
+            Value::Bool(b) => Ok(Value::Float(if *b { 1.0 } else { 0.0 })),
+            Value::Text(t) | Value::Safe(t) => t.trim().parse::<f64>().map(Value::Float).map_err(|_| fail()),
+            _ => Err(fail()),
+        }
+    }
+
+    // parse_list
+    // [expression, expression, ...]
+    //
+    // Pushes a list made of the evaluated expressions, a trailing comma is
+    // accepted
+    fn parse_list<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        let mut list = Vec::new();
+
+        while !iter.match_next(TokenTypes::RBRACKET) {
+            if iter.look().is_none() {
+                ctx.errors_push(ErrorKind::Syntax, String::from("missing closing ]"));
+                break;
+            }
+
+            parse_expression(iter, ctx);
+            list.push(ctx.stack_pop().unwrap_or(Value::Null));
+
+            if !iter.match_next(TokenTypes::COMMA) {
+                if !iter.match_next(TokenTypes::RBRACKET) {
+                    ctx.errors_push(ErrorKind::Syntax, String::from("missing closing ]"));
+                }
+                break;
+            }
+        }
+
+        ctx.stack_push(Value::List(list));
+    }
+
+    // parse_map
+    // {expression: expression, expression: expression, ...}
+    //
+    // Pushes a map made of the evaluated pairs, keys must evaluate to text.
+    // A trailing comma is accepted
+    fn parse_map<I>(iter: &mut I, ctx: &mut context::Context)
+    where I: TokenatorTrait {
+        let mut map = ValueMap::new();
+
+        while !iter.match_next(TokenTypes::RBRACE) {
+            if iter.look().is_none() {
+                ctx.errors_push(ErrorKind::Syntax, String::from("missing closing }"));
+                break;
+            }
+
+            parse_expression(iter, ctx);
+            let key = ctx.stack_pop().unwrap_or(Value::Null);
+
+            if !iter.match_next(TokenTypes::COLON) {
+                ctx.errors_push(ErrorKind::Syntax, format!("missing : after map key {}", describe(&key)));
+            }
+
+            parse_expression(iter, ctx);
+            let value = ctx.stack_pop().unwrap_or(Value::Null);
+
+            match key.plain() {
+                Value::Text(k) => {
+                    map.insert(k, value);
+                },
+                key => ctx.errors_push(ErrorKind::Operation, format!("map key must be text, not {}", describe(&key))),
+            }
+
+            if !iter.match_next(TokenTypes::COMMA) {
+                if !iter.match_next(TokenTypes::RBRACE) {
+                    ctx.errors_push(ErrorKind::Syntax, String::from("missing closing }"));
+                }
+                break;
+            }
+        }
+
+        ctx.stack_push(Value::Map(map));
     }
 
-    fn parse_primary<I>(iter: &mut I, ctx: &mut context::Context)
+    // Subscript is one step down into a value: .name, [id] or
+    // [start:stop:step]. A slice with a bound that isn't an int keeps why,
+    // it fails when it's taken like an index out of range.
+    enum Subscript {
+        Attribute(String),
+        Index(Value),
+        Slice(Result<[Option<i64>; 3], String>),
+    }
+
+    // parse_variable
+    // identifier[expression].identifier[expression]...
+    //
+    // Pushes the element of the variable called key addressed by the
+    // subscripts that follow it. The variable is followed by reference, so
+    // only the element reached is copied.
+    fn parse_variable<I>(iter: &mut I, ctx: &mut context::Context, key: String)
     where I: TokenatorTrait {
-        if iter.match_next(TokenTypes::NUMBER) {
-            let data = iter.look_back().unwrap().get_data();
-            match data.parse::<i64>() {
-                Ok(n) => ctx.stack_push(StackType::Number(n)),
-                Err(e) => ctx.errors_push(String::from(format!("impossible converting {} to u64 - {}", data, e))),
-            }
-        }
-        else if iter.match_next(TokenTypes::STRING) {
-            let data = iter.look_back().unwrap().get_data();
-            ctx.stack_push(StackType::Text(data));
-        }
-        else if iter.match_next(TokenTypes::TRUE) {
-            ctx.stack_push(StackType::Bool(true));
+        let subscripts = parse_subscripts(iter, ctx, key.clone());
+
+        let mut failures = Vec::new();
+        let data = match ctx.env_get(&key) {
+            Some(data) => descend(data, &subscripts, &mut failures),
+            None => {
+                failures.push((ErrorKind::Lookup, format!("invalid identifier {}", key)));
+                descend(&Value::Null, &subscripts, &mut failures)
+            },
+        };
+
+        for (kind, error) in failures {
+            ctx.errors_push(kind, error);
         }
-        else if iter.match_next(TokenTypes::FALSE) {
-            ctx.stack_push(StackType::Bool(false));
+        ctx.stack_push(data);
+    }
+
+    // parse_subscript
+    // <primary>[expression].identifier[expression]...
+    //
+    // Replaces the value on top of the stack by the element addressed by each
+    // subscript or attribute, so nested data like orders[0]["items"][2] or
+    // users[0].address.city can be reached
+    fn parse_subscript<I>(iter: &mut I, ctx: &mut context::Context, path: String)
+    where I: TokenatorTrait {
+        let subscripts = parse_subscripts(iter, ctx, path);
+        if subscripts.is_empty() {
+            return;
         }
-        else if iter.match_next(TokenTypes::LPAREN) {
-            parse_expression(iter, ctx);
 
-            if !iter.match_next(TokenTypes::RPAREN) {
-                ctx.errors_push(String::from("missing closing"));
-            }
+        let container = ctx.stack_pop().unwrap_or(Value::Null);
+        let mut failures = Vec::new();
+        let data = descend(&container, &subscripts, &mut failures);
+
+        for (kind, error) in failures {
+            ctx.errors_push(kind, error);
         }
-        else if iter.match_next(TokenTypes::IDENTIFIER) {
-            let key = iter.look_back().unwrap().get_data();
-            if !ctx.env_key_exists(&key) {
-                ctx.errors_push(String::from(format!("invalid identifier {}", key)));
-            }
+        ctx.stack_push(data);
+    }
 
-            let found_left_bracket = match iter.look() {
-                Some(tk) => tk.get_type() == TokenTypes::LBRACKET,
-                None => false,
-            };
+    // parse_subscripts
+    //
+    // Returns the subscripts and attributes that follow a primary, each with
+    // the path that names it in errors: the value an attribute is looked up
+    // on, or the value an index or a slice reaches. Paths start at path.
+    fn parse_subscripts<I>(iter: &mut I, ctx: &mut context::Context, mut path: String) -> Vec<(Subscript, String)>
+    where I: TokenatorTrait {
+        let mut ret = Vec::new();
 
-            if found_left_bracket {
-                iter.next();
-                parse_primary(iter, ctx);
+        loop {
+            if iter.match_next(TokenTypes::DOT) {
+                if !iter.match_next(TokenTypes::IDENTIFIER) {
+                    ctx.errors_push(ErrorKind::Syntax, format!("expected attribute name after {}.", path));
+                    break;
+                }
 
-                if !iter.match_next(TokenTypes::RBRACKET) {
-                    ctx.errors_push(String::from("missing closing ]"));
+                let name = iter.look_back().unwrap().get_data();
+                let next = format!("{}.{}", path, name);
+                ret.push((Subscript::Attribute(name), path));
+                path = next;
+            }
+            else if iter.match_next(TokenTypes::LBRACKET) {
+                let start = parse_slice_bound(iter, ctx);
+
+                if iter.match_next(TokenTypes::COLON) {
+                    let (slice, next) = parse_slice(iter, ctx, start, path);
+                    ret.push((slice, next.clone()));
+                    path = next;
+                    continue;
                 }
 
-                match ctx.stack_pop() {
-                    Some(d) => match d {
-                        StackType::Text(id) => ctx.stack_push_from_env_map(&key, &id),
-                        StackType::Number(id) => ctx.stack_push_from_env_vector(&key, id as usize),
-                        _ => ctx.errors_push(String::from("invalid id")),
-                    },
-                    None => {
-                        ctx.errors_push(String::from(format!("no data retrieved from the stack")));
-                    }
+                if !iter.match_next(TokenTypes::RBRACKET) {
+                    ctx.errors_push(ErrorKind::Syntax, String::from("missing closing ]"));
                 }
+
+                let id = start.unwrap_or(Value::Null);
+                let next = match &id {
+                    Value::Text(t) | Value::Safe(t) => format!("{}[{:?}]", path, t),
+                    _ => format!("{}[{}]", path, id),
+                };
+                ret.push((Subscript::Index(id), next.clone()));
+                path = next;
             }
             else {
-                ctx.stack_push_from_env(&key);
+                break;
             }
         }
-        else {
-            ctx.errors_push(String::from(format!("unexpected token {:#?}", iter.look())));
+
+        ret
+    }
+    // parse_slice_bound
+    //
+    // Returns the evaluated bound of a slice, None when it was omitted
+    fn parse_slice_bound<I>(iter: &mut I, ctx: &mut context::Context) -> Option<Value>
+    where I: TokenatorTrait {
+        match iter.look() {
+            Some(tk) if tk.get_type() == TokenTypes::COLON ||
+                        tk.get_type() == TokenTypes::RBRACKET => None,
+            _ => {
+                parse_expression(iter, ctx);
+                ctx.stack_pop()
+            },
         }
     }
 
-    fn compute_binary(left: StackType, right: StackType, oper: TokenTypes) -> Result<StackType, String> {
-        let ret: Result<StackType, String>;
+    // parse_slice
+    // <primary>[start:stop:step]
+    //
+    // Returns the slice, the first : is already consumed, and path with the
+    // slice appended
+    fn parse_slice<I>(iter: &mut I, ctx: &mut context::Context, start: Option<Value>, path: String) -> (Subscript, String)
+    where I: TokenatorTrait {
+        let stop = parse_slice_bound(iter, ctx);
+        let step = if iter.match_next(TokenTypes::COLON) {
+            parse_slice_bound(iter, ctx)
+        }
+        else {
+            None
+        };
 
-        if matches!(left, StackType::Text(_)) && matches!(right, StackType::Text(_)) {
-            ret = compute_strings(left, right, oper);
+        if !iter.match_next(TokenTypes::RBRACKET) {
+            ctx.errors_push(ErrorKind::Syntax, String::from("missing closing ]"));
         }
-        else if matches!(left, StackType::Number(_)) && matches!(right, StackType::Number(_)) {
-            ret = compute_numbers(left, right, oper);
+
+        let show = |bound: &Option<Value>| bound.as_ref().map_or(String::new(), |b| b.to_string());
+        let path = match &step {
+            Some(_) => format!("{}[{}:{}:{}]", path, show(&start), show(&stop), show(&step)),
+            None => format!("{}[{}:{}]", path, show(&start), show(&stop)),
+        };
+
+        let mut bounds = [None; 3];
+        let mut invalid = None;
+        for (i, bound) in [start, stop, step].iter().enumerate() {
+            match bound {
+                None | Some(Value::Null) => (),
+                Some(Value::Int(n)) => bounds[i] = Some(*n),
+                Some(b) => invalid = invalid.or_else(|| Some(format!("bounds must be int, not {}", b.type_name()))),
+            }
         }
-        else if matches!(left, StackType::Bool(_)) &&
-                matches!(right, StackType::Bool(_)) {
-            let a = match left {
-                StackType::Bool(b) => b,
-                _ => return Err(String::from("run to the hills")),
-            };
 
-            let b = match right {
-                StackType::Bool(b) => b,
-                _ => return Err(String::from("run to the hills")),
+        let slice = match invalid {
+            Some(e) => Err(e),
+            None => Ok(bounds),
+        };
+        (Subscript::Slice(slice), path)
+    }
+
+    // descend
+    //
+    // Returns a copy of the element of data addressed by subscripts. Each
+    // step borrows from the one before, so nothing else is copied. A step
+    // that fails is added to failures and the ones after it go on from null.
+    fn descend(data: &Value, subscripts: &[(Subscript, String)], failures: &mut Vec<(ErrorKind, String)>) -> Value {
+        let mut current = Cow::Borrowed(data);
+
+        for (subscript, path) in subscripts {
+            let next = match &current {
+                Cow::Borrowed(data) => step(data, subscript, path),
+                Cow::Owned(data) => step(data, subscript, path).map(|v| Cow::Owned(v.into_owned())),
             };
 
-            ret = match oper {
-                TokenTypes::AND => Ok(StackType::Bool(a && b)),
-                TokenTypes::OR  => Ok(StackType::Bool(a || b)),
-                TokenTypes::EQ  => Ok(StackType::Bool(a == b)),
-                TokenTypes::NE  => Ok(StackType::Bool(a != b)),
-                _ => return Err(String::from(format!("Operator {:#?} invalid for booleans", oper))),
+            current = match next {
+                Ok(v) => v,
+                Err(failure) => {
+                    failures.push(failure);
+                    Cow::Owned(Value::Null)
+                },
             };
         }
-        else {
-            let lt = match left {
-                StackType::Text(t)   => format!("text({})", t),
-                StackType::Bool(b)   => format!("bool({})", b),
-                StackType::Number(n) => format!("number({})", n),
-            };
 
-            let rt = match right {
-                StackType::Text(t)   => format!("text({})", t),
-                StackType::Bool(b)   => format!("bool({})", b),
-                StackType::Number(n) => format!("number({})", n),
-            };
+        current.into_owned()
+    }
 
-            return Err(String::from(format!("mismatch types {} {:?} {}", lt, oper, rt)));
+    // step
+    //
+    // Returns the element of data addressed by subscript, path names it in
+    // the error when there's none
+    fn step<'v>(data: &'v Value, subscript: &Subscript, path: &str) -> Result<Cow<'v, Value>, (ErrorKind, String)> {
+        match subscript {
+            Subscript::Attribute(name) => match data.attribute(name) {
+                Some(v) => Ok(Cow::Borrowed(v)),
+                None => Err((ErrorKind::Lookup, format!("no attribute `{}` on `{}`", name, path))),
+            },
+            Subscript::Index(id) => data.index(id)
+                .map_err(|e| (ErrorKind::Lookup, format!("invalid id {}: {}", path, e))),
+            Subscript::Slice(bounds) => bounds.clone()
+                .and_then(|[start, stop, step]| data.slice(start, stop, step))
+                .map(Cow::Owned)
+                .map_err(|e| (ErrorKind::Operation, format!("invalid slice {}: {}", path, e))),
         }
+    }
 
-        ret
+    // describe a value the way it shows up in error messages: type(value)
+    fn describe(value: &Value) -> String {
+        format!("{}({})", value.type_name(), value)
     }
 
-    fn compute_strings(left: StackType, right: StackType, oper: TokenTypes) -> Result<StackType, String> {
-        let a = match left {
-            StackType::Text(b) => b,
-            _ => return Err(String::from("run to the hills")),
-        };
+    // compute
+    //
+    // Applies oper to left and right, converting text operands first when
+    // coercion is enabled in Amps
+    fn compute(left: Value, right: Value, oper: TokenTypes, ctx: &context::Context) -> Result<Value, String> {
+        let (left, right) = (left.plain(), right.plain());
+        if !ctx.coercion() {
+            return compute_binary(left, right, oper);
+        }
 
-        let b = match right {
-            StackType::Text(b) => b,
-            _ => return Err(String::from("run to the hills")),
+        let (left, right) = match (left, right) {
+            (Value::Text(t), r @ Value::Int(_)) | (Value::Text(t), r @ Value::Float(_)) => {
+                match text_to_number(&t) {
+                    Some(l) => (l, r),
+                    None => (Value::Text(t), r),
+                }
+            },
+            (l @ Value::Int(_), Value::Text(t)) | (l @ Value::Float(_), Value::Text(t)) => {
+                match text_to_number(&t) {
+                    Some(r) => (l, r),
+                    None => (l, Value::Text(t)),
+                }
+            },
+            // texts only have + of their own, any other arithmetic is done
+            // on the numbers they hold
+            (Value::Text(a), Value::Text(b)) if is_arithmetic(&oper) => {
+                match (text_to_number(&a), text_to_number(&b)) {
+                    (Some(l), Some(r)) => (l, r),
+                    _ => (Value::Text(a), Value::Text(b)),
+                }
+            },
+            pair => pair,
         };
 
-        let result = match oper {
-            TokenTypes::NE   => StackType::Bool(a != b),
-            TokenTypes::EQ   => StackType::Bool(a == b),
-            TokenTypes::GT   => StackType::Bool(a > b),
-            TokenTypes::GE   => StackType::Bool(a >= b),
-            TokenTypes::LT   => StackType::Bool(a < b),
-            TokenTypes::LE   => StackType::Bool(a <= b),
-            TokenTypes::PLUS => StackType::Text(a + &b),
-            _ => return Err(String::from(format!("Operator {:#?} invalid for strings", oper))),
-        };
+        match (left, right) {
+            (Value::Text(t), r) if oper == TokenTypes::PLUS && is_scalar(&r) => {
+                Ok(Value::Text(format!("{}{}", t, r)))
+            },
+            (l, Value::Text(t)) if oper == TokenTypes::PLUS && is_scalar(&l) => {
+                Ok(Value::Text(format!("{}{}", l, t)))
+            },
+            (l, r) => compute_binary(l, r, oper),
+        }
+    }
 
-        Ok(result)
+    fn is_arithmetic(oper: &TokenTypes) -> bool {
+        matches!(oper, TokenTypes::MINUS | TokenTypes::STAR | TokenTypes::SLASH | TokenTypes::PERCENT |
+                       TokenTypes::DOUBLESLASH | TokenTypes::DOUBLESTAR)
     }
 
-    fn compute_numbers(left: StackType, right: StackType, oper: TokenTypes) -> Result<StackType, String> {
-        let a = match left {
-            StackType::Number(b) => b,
-            _ => return Err(String::from("run to the hills")),
-        };
+    fn is_scalar(value: &Value) -> bool {
+        !matches!(value, Value::List(_) | Value::Map(_))
+    }
+
+    // the number a text holds, an int when it has no fraction
+    fn text_to_number(text: &str) -> Option<Value> {
+        let text = text.trim();
+        match text.parse::<i64>() {
+            Ok(n) => Some(Value::Int(n)),
+            Err(_) => text.parse::<f64>().ok().filter(|f| f.is_finite()).map(Value::Float),
+        }
+    }
+
+    fn compute_binary(left: Value, right: Value, oper: TokenTypes) -> Result<Value, String> {
+        if oper == TokenTypes::IN {
+            return right.contains(&left).map(Value::Bool);
+        }
+
+        match (left.plain(), right.plain()) {
+            (Value::Text(a), Value::Text(b))     => compute_strings(a, b, oper),
+            (Value::Int(a), Value::Int(b))       => compute_numbers(a, b, oper),
+            (Value::Float(a), Value::Float(b))   => compute_floats(a, b, oper),
+            (Value::Int(a), Value::Float(b))     => compute_floats(a as f64, b, oper),
+            (Value::Float(a), Value::Int(b))     => compute_floats(a, b as f64, oper),
+            (Value::Bool(a), Value::Bool(b))     => {
+                match oper {
+                    TokenTypes::AND => Ok(Value::Bool(a && b)),
+                    TokenTypes::OR  => Ok(Value::Bool(a || b)),
+                    TokenTypes::EQ  => Ok(Value::Bool(a == b)),
+                    TokenTypes::NE  => Ok(Value::Bool(a != b)),
+                    _ => Err(format!("Operator {:#?} invalid for booleans", oper)),
+                }
+            },
+
+            // lists, maps and null can only be compared for (in)equality;
+            // null compares against anything
+            (left, right) if left.type_name() == right.type_name() ||
+                             left == Value::Null || right == Value::Null => {
+                match oper {
+                    TokenTypes::EQ  => Ok(Value::Bool(left == right)),
+                    TokenTypes::NE  => Ok(Value::Bool(left != right)),
+                    _ => Err(format!("Operator {:#?} invalid for {} {}",
+                                     oper, describe(&left), describe(&right))),
+                }
+            },
+
+            (left, right) => {
+                Err(format!("mismatch types {} {:?} {}", describe(&left), oper, describe(&right)))
+            },
+        }
+    }
 
-        let b = match right {
-            StackType::Number(b) => b,
-            _ => return Err(String::from("run to the hills")),
+    fn compute_strings(a: String, b: String, oper: TokenTypes) -> Result<Value, String> {
+        let result = match oper {
+            TokenTypes::NE   => Value::Bool(a != b),
+            TokenTypes::EQ   => Value::Bool(a == b),
+            TokenTypes::GT   => Value::Bool(a > b),
+            TokenTypes::GE   => Value::Bool(a >= b),
+            TokenTypes::LT   => Value::Bool(a < b),
+            TokenTypes::LE   => Value::Bool(a <= b),
+            TokenTypes::PLUS => Value::Text(a + &b),
+            _ => return Err(format!("Operator {:#?} invalid for strings", oper)),
         };
 
+        Ok(result)
+    }
+
+    fn compute_numbers(a: i64, b: i64, oper: TokenTypes) -> Result<Value, String> {
         let result = match oper {
-            TokenTypes::NE      => StackType::Bool(a != b),
-            TokenTypes::EQ      => StackType::Bool(a == b),
-            TokenTypes::GT      => StackType::Bool(a > b),
-            TokenTypes::GE      => StackType::Bool(a >= b),
-            TokenTypes::LT      => StackType::Bool(a < b),
-            TokenTypes::LE      => StackType::Bool(a <= b),
+            TokenTypes::NE      => Value::Bool(a != b),
+            TokenTypes::EQ      => Value::Bool(a == b),
+            TokenTypes::GT      => Value::Bool(a > b),
+            TokenTypes::GE      => Value::Bool(a >= b),
+            TokenTypes::LT      => Value::Bool(a < b),
+            TokenTypes::LE      => Value::Bool(a <= b),
             TokenTypes::PLUS    => {
                 match a.checked_add(b) {
-                    Some(i) => StackType::Number(i),
-                    None    => return Err(String::from(format!("{} + {} overflows", a, b))),
+                    Some(i) => Value::Int(i),
+                    None    => return Err(format!("{} + {} overflows", a, b)),
                 }
             },
             TokenTypes::MINUS   => {
                 match a.checked_sub(b) {
-                    Some(i) => StackType::Number(i),
-                    None    => return Err(String::from(format!("{} - {} underflows", a, b))),
+                    Some(i) => Value::Int(i),
+                    None    => return Err(format!("{} - {} underflows", a, b)),
                 }
             },
             TokenTypes::STAR    => {
                 match a.checked_mul(b) {
-                    Some(i) => StackType::Number(i),
-                    None    => return Err(String::from(format!("{} * {} overflows", a, b))),
+                    Some(i) => Value::Int(i),
+                    None    => return Err(format!("{} * {} overflows", a, b)),
                 }
             }
             TokenTypes::SLASH   => {
                 match a.checked_div(b) {
-                    Some(i) => StackType::Number(i),
-                    None    => return Err(String::from(format!("{} / {} division by 0", a, b))),
+                    Some(i) => Value::Int(i),
+                    None    => return Err(format!("{} / {} division by 0", a, b)),
                 }
             },
             TokenTypes::PERCENT => {
                 match a.checked_rem(b) {
-                    Some(i) => StackType::Number(i),
-                    None    => return Err(String::from(format!("{} / {} division by 0", a, b))),
+                    Some(i) => Value::Int(i),
+                    None    => return Err(format!("{} / {} division by 0", a, b)),
+                }
+            },
+            TokenTypes::DOUBLESLASH => {
+                if b == 0 {
+                    return Err(format!("{} // {} division by 0", a, b));
+                }
+
+                // rounds toward negative infinity, -7 // 2 is -4
+                match (a.checked_div(b), a.checked_rem(b)) {
+                    (Some(q), Some(r)) if r != 0 && ((r < 0) != (b < 0)) => Value::Int(q - 1),
+                    (Some(q), Some(_)) => Value::Int(q),
+                    _ => return Err(format!("{} // {} overflows", a, b)),
+                }
+            },
+            TokenTypes::DOUBLESTAR => {
+                // a negative exponent can only give a fraction
+                if b < 0 {
+                    return compute_floats(a as f64, b as f64, oper);
+                }
+
+                let power = if b > u32::MAX as i64 { None } else { a.checked_pow(b as u32) };
+                match power {
+                    Some(i) => Value::Int(i),
+                    None    => return Err(format!("{} ** {} overflows", a, b)),
+                }
+            },
+            TokenTypes::AMPERSAND => Value::Int(a & b),
+            TokenTypes::PIPE      => Value::Int(a | b),
+            TokenTypes::CARET     => Value::Int(a ^ b),
+            TokenTypes::LSHIFT    => {
+                if !(0..64).contains(&b) {
+                    return Err(format!("{} << {} shift out of range", a, b));
+                }
+
+                let i = a << b;
+                if i >> b != a {
+                    return Err(format!("{} << {} overflows", a, b));
+                }
+                Value::Int(i)
+            },
+            TokenTypes::RSHIFT    => {
+                if !(0..64).contains(&b) {
+                    return Err(format!("{} >> {} shift out of range", a, b));
+                }
+                Value::Int(a >> b)
+            },
+            _ => return Err(format!("Operator {:#?} invalid for numbers", oper)),
+        };
+
+        Ok(result)
+    }
+
+    fn compute_floats(a: f64, b: f64, oper: TokenTypes) -> Result<Value, String> {
+        let result = match oper {
+            TokenTypes::NE      => Value::Bool(a != b),
+            TokenTypes::EQ      => Value::Bool(a == b),
+            TokenTypes::GT      => Value::Bool(a > b),
+            TokenTypes::GE      => Value::Bool(a >= b),
+            TokenTypes::LT      => Value::Bool(a < b),
+            TokenTypes::LE      => Value::Bool(a <= b),
+            TokenTypes::PLUS    => Value::Float(a + b),
+            TokenTypes::MINUS   => Value::Float(a - b),
+            TokenTypes::STAR    => Value::Float(a * b),
+            TokenTypes::SLASH   => {
+                if b == 0.0 {
+                    return Err(format!("{} / {} division by 0", a, b));
+                }
+                Value::Float(a / b)
+            },
+            TokenTypes::PERCENT => {
+                if b == 0.0 {
+                    return Err(format!("{} / {} division by 0", a, b));
+                }
+                Value::Float(a % b)
+            },
+            TokenTypes::DOUBLESLASH => {
+                if b == 0.0 {
+                    return Err(format!("{} // {} division by 0", a, b));
                 }
+                Value::Float((a / b).floor())
             },
-            _ => return Err(String::from(format!("Operator {:#?} invalid for numbers", oper))),
+            TokenTypes::DOUBLESTAR => Value::Float(a.powf(b)),
+            _ => return Err(format!("Operator {:#?} invalid for numbers", oper)),
         };
 
         Ok(result)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::{env, process, thread};
+
+    use crate::amps::Amps;
+    use crate::engine::context::Truthiness;
+    use crate::engine::error::AmpsError;
+    use crate::engine::escape::Escape;
+    use crate::engine::value::{Value, ValueMap};
+
+    // renders the template loaded in amps, returns the output, written even
+    // when there are errors, and the errors
+    fn render_with(amps: &Amps) -> (String, Vec<AmpsError>) {
+        let mut out: Vec<u8> = Vec::new();
+        let errors = amps.render_to(&mut out).err().unwrap_or_default();
+        (String::from_utf8(out).unwrap(), errors)
+    }
+
+    fn render(template: &str) -> (String, Vec<String>) {
+        let mut amps = Amps::new();
+        amps.load_template(template.to_string());
+        let (output, errors) = render_with(&amps);
+        (output, errors.iter().map(|e| e.message().to_string()).collect())
+    }
+
+    fn orders() -> Value {
+        let mut order = ValueMap::new();
+        order.insert(String::from("id"), Value::from(7));
+        order.insert(String::from("items"), Value::from(vec!["pen", "ink", "pad"]));
+        Value::from(vec![Value::Map(order)])
+    }
+
+    #[test]
+    fn deep_indexing() {
+        let mut amps = Amps::new();
+        amps.set("orders", orders());
+        amps.load_template(String::from(concat!(
+            "{= orders[0][\"items\"][2] =} {= orders[0][\"items\"][-1][1:] =} ",
+            "{= orders[0][\"items\"][1:][0] =} {= orders[0][\"id\"] + 1 =}")));
+        assert_eq!(amps.render_to_string().unwrap(), "pad ad ink 8");
+
+        amps.load_template(String::from("{= orders[1] =}{= orders[0][\"items\"][\"x\"] =}{= orders[0][\"total\"][0] =}"));
+        let (output, errors) = render_with(&amps);
+        assert_eq!(output, "nullnullnull");
+        let errors: Vec<&str> = errors.iter().map(|e| e.message()).collect();
+        assert_eq!(errors, vec![
+            "invalid id orders[1]: index 1 out of range for list of length 1",
+            "invalid id orders[0][\"items\"][\"x\"]: list index must be int, not text",
+            "invalid id orders[0][\"total\"]: key \"total\" not found",
+            "invalid id orders[0][\"total\"][0]: null cannot be indexed",
+        ]);
+    }
+
+    #[test]
+    fn attribute_lookup() {
+        let mut amps = Amps::new();
+        amps.set("orders", orders());
+        amps.load_template(String::from(concat!(
+            "{% set user = {\"name\": \"Ann\", \"address\": {\"city\": \"Oslo\"}, \"emails\": [\"a@x\", \"b@x\"]} %}",
+            "{% set users = [user] %}",
+            "{= user.address.city =}|{= users[0].emails[1] =}|{= users[0].address[\"city\"][0] =}|",
+            "{= orders[0].items[-1] =}")));
+        assert_eq!(amps.render_to_string().unwrap(), "Oslo|b@x|O|pad");
+
+        let (output, errors) = render(concat!(
+            "{% set user = {\"name\": \"Ann\", \"address\": {\"city\": \"Oslo\"}} %}",
+            "{= user.address.zip =}|{= user.name.first =}|{= user.address.zip.code =}|{= nobody.x =}"));
+        assert_eq!(output, "null|null|null|null");
+        assert_eq!(errors, vec![
+            "no attribute `zip` on `user.address`",
+            "no attribute `first` on `user.name`",
+            "no attribute `zip` on `user.address`",
+            "no attribute `code` on `user.address.zip`",
+            "invalid identifier nobody",
+            "no attribute `x` on `nobody`",
+        ]);
+    }
+
+    #[test]
+    fn list_and_map_literals() {
+        let (output, errors) = render(concat!(
+            "{= [1, 2.5, \"a\", [true, null]] =}|{= {\"k\": \"v\", \"n\": 2} =}|{= [] =}{= {} =}|",
+            "{= [1, 2,] =}|{= {\"a\": [1, {\"b\": 2}]}[\"a\"][1].b =}|{= [1, 2] eq [1, 2] =}|",
+            "{= str([1 + 1, \"x\"]) =}|{% set t = {\"on\": \"yes\"} %}{= t.on =}"));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(output, "[1, 2.5, \"a\", [true, null]]|{\"k\": \"v\", \"n\": 2}|[]{}|[1, 2]|2|true|[2, \"x\"]|yes");
+
+        let (_, errors) = render("{= [1, 2 =}|{= {\"a\" 1} =}|{= {1: 2} =}|{= {\"a\": 1 =}");
+        assert_eq!(errors, vec![
+            "missing closing ]",
+            "missing : after map key text(a)",
+            "map key must be text, not int(1)",
+            "missing closing }",
+        ]);
+    }
+
+    #[test]
+    fn membership() {
+        let mut amps = Amps::new();
+        amps.set("roles", vec!["admin", "dev"]);
+        amps.load_template(String::from(concat!(
+            "{% if \"admin\" in roles %}admin{% endif %}|{= \"ops\" in roles =} {= \"ops\" not in roles =}|",
+            "{= \"k\" in {\"k\": 1} =} {= \"v\" in {\"k\": \"v\"} =}|{= \"ell\" in \"hello\" =} {= \"\" in \"\" =}|",
+            "{= 2 in [1, 2] and 3 not in [1, 2] =}")));
+        assert_eq!(amps.render_to_string().unwrap(), "admin|false true|true false|true true|true");
+
+        let (output, errors) = render("{= 1 in {\"a\": 1} =}|{= 1 in \"1\" =}|{= 1 in 5 =}");
+        assert_eq!(output, "null|null|null");
+        assert_eq!(errors, vec![
+            "map key must be text, not int",
+            "cannot search int in text",
+            "int is not a container",
+        ]);
+    }
+
+    #[test]
+    fn inline_if() {
+        let mut amps = Amps::new();
+        amps.set("enabled", true);
+        amps.set("items", Vec::<Value>::new());
+        amps.load_template(String::from(concat!(
+            "{= \"yes\" if enabled else \"no\" =}|{= \"yes\" if not enabled else \"no\" =}|",
+            "{= 1 if false else 2 if false else 3 =}|{= (1 + 2 if enabled else 0) * 2 =}|",
+            "{= items[0] if items ne [] else \"empty\" =}|{= 1 / 0 if false else \"lazy\" =}")));
+        assert_eq!(amps.render_to_string().unwrap(), "yes|no|3|6|empty|lazy");
+
+        let (output, errors) = render("{= 1 if 1 else 2 =}|{= 1 if true =}");
+        assert_eq!(output, "2|null");
+        assert_eq!(errors, vec![
+            "inline if condition must evaluates to boolean, not int(1)",
+            "inline if without else",
+        ]);
+    }
+
+    #[test]
+    fn slices_in_templates() {
+        let mut amps = Amps::new();
+        amps.set("items", vec![1, 2, 3, 4]);
+        amps.set("name", "Anna-Maria");
+        amps.load_template(String::from(concat!(
+            "{= items[-1] =}|{= items[1:3] =}|{= items[::-2] =}|{= name[:4] =}|{= name[-5:] =}|",
+            "{= items[1 + 1:] =}|{= [1, 2, 3][1:][0] =}")));
+        assert_eq!(amps.render_to_string().unwrap(), "4|[2, 3]|[4, 2]|Anna|Maria|[3, 4]|2");
+
+        amps.load_template(String::from("{= items[9] =}|{= items[::0] =}|{= items[\"a\":] =}"));
+        let (output, errors) = render_with(&amps);
+        assert_eq!(output, "null|null|null");
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(), vec![
+            "invalid id items[9]: index 9 out of range for list of length 4",
+            "invalid slice items[::0]: slice step cannot be zero",
+            "invalid slice items[a:]: bounds must be int, not text",
+        ]);
+    }
+
+    #[test]
+    fn arithmetic_and_bitwise_operators() {
+        let (output, errors) = render(concat!(
+            "{= 2 ** 10 =} {= 2 ** 3 ** 2 =} {= -2 ** 2 =} {= 2 ** -1 =} {= 2.0 ** 0.5 gt 1.41 =}|",
+            "{= 7 // 2 =} {= -7 // 2 =} {= 7 // -2 =} {= -7.5 // 2 =}|",
+            "{= 12 & 10 =} {= 12 | 3 =} {= 12 ^ 10 =} {= ~0 =} {= 1 << 4 =} {= -16 >> 2 =}|",
+            "{= 1 | 2 ^ 3 & 4 =} {= 1 + 2 << 1 =} {= 2 * 3 ** 2 =} {= 1 | 6 eq 7 =}"));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(output, "1024 512 -4 0.5 true|3 -4 -4 -4.0|8 15 6 -1 16 -4|3 6 18 true");
+
+        let (output, errors) = render(concat!(
+            "{= 9223372036854775807 + 1 =}|{= 2 ** 64 =}|{= -9223372036854775807 - 2 =}|",
+            "{= 1 << 64 =}|{= 1 << 63 =}|{= 1 >> -1 =}|{= 1 // 0 =}|{= 4 * 4611686018427387904 =}|",
+            "{= 1.5 & 1 =}"));
+        assert_eq!(output, "||||||||");
+        assert_eq!(errors, vec![
+            "9223372036854775807 + 1 overflows",
+            "2 ** 64 overflows",
+            "-9223372036854775807 - 2 underflows",
+            "1 << 64 shift out of range",
+            "1 << 63 overflows",
+            "1 >> -1 shift out of range",
+            "1 // 0 division by 0",
+            "4 * 4611686018427387904 overflows",
+            "Operator AMPERSAND invalid for numbers",
+        ]);
+    }
+
+    #[test]
+    fn match_case() {
+        let template = concat!("{% match kind %} skipped {% case \"a\", \"b\" %}AB{% case 3 %}three",
+                               "{% case [1] %}list{% default %}other{% endmatch %}");
+        for (kind, expected) in [(Value::from("b"), "AB"), (Value::from(3.0), "three"),
+                                 (Value::from(vec![1]), "list"), (Value::from("3"), "other")] {
+            let mut amps = Amps::new();
+            amps.set("kind", kind);
+            amps.load_template(String::from(template));
+            assert_eq!(amps.render_to_string().unwrap(), expected);
+        }
+
+        let (output, errors) = render(concat!(
+            "{% match 1 %}{% case 1 %}one{% case 2, 1.0 %}again{% endmatch %}|",
+            "{% if false %}{% match x %}{% case y %}no{% endmatch %}{% endif %}|",
+            "{% match 1 %}{% default %}d{% case 2 %}two{% endmatch %}|{% case 1 %}"));
+        assert_eq!(output, "one||dtwo|");
+        assert_eq!(errors, vec![
+            "duplicate case float(1.0) at line 1, already handled at line 1",
+            "case at line 1 after default of the match started at line 1",
+            "mismatch case at line 1, there is no open block",
+        ]);
+    }
+
+    #[test]
+    fn format_specs_in_templates() {
+        let mut amps = Amps::new();
+        amps.set("price", 9.5);
+        amps.set("mask", 255);
+        amps.set_autoescape(Escape::Html);
+        amps.load_template(String::from(concat!(
+            "[{= price:>8.2 =}][{= mask:#06x =}][{= price * 2:.1 =}][{= format(mask, \"b\") =}]",
+            "[{= \"<b>\":>5 =}][{= safe(\"<b>\"):>5 =}]")));
+        assert_eq!(amps.render_to_string().unwrap(), "[    9.50][0x00ff][19.0][11111111][  &lt;b&gt;][  <b>]");
+
+        let (output, errors) = render("{= 1.5:x =}|{= format(1, \">5q\") =}");
+        assert_eq!(output, "1.5|null");
+        assert_eq!(errors, vec!["format x expects an int, not float", "format: invalid format spec \">5q\""]);
+    }
+
+    #[test]
+    fn conversions() {
+        let (output, errors) = render(concat!(
+            "{= int(\" 42 \") + 1 =} {= int(3.9) =} {= int(-3.9) =} {= int(true) =} {= float(\"2.5\") * 2 =} ",
+            "{= float(3) =} {= str(12) + \"!\" =} {= str([1, \"a\"]) =} {= bool(\"false\") =} {= bool(\"no\") =} ",
+            "{= bool(0) =} {= bool([1]) =}|{% macro n() %}7{% endmacro %}{= int(n()) * 2 =}"));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(output, "43 3 -3 1 5.0 3.0 12! [1, \"a\"] false true false true|14");
+
+        let (output, errors) = render("{= int(\"4x\") =}|{= float(null) =}|{= int(float(\"inf\")) =}|{= int(1, 2) =}");
+        assert_eq!(output, "null|null|null|null");
+        assert_eq!(errors, vec![
+            "int: cannot convert text(4x) to int",
+            "float: cannot convert null(null) to float",
+            "int: cannot convert float(inf) to int",
+            "int: expects 1 argument, found 2",
+        ]);
+    }
+
+    #[test]
+    fn coercion() {
+        let template = "{= \"n=\" + 5 =}|{= \"4\" * 2 =}|{= \"1.5\" + 1 =}|{= \"6\" - \"2\" =}|{= \"a\" - 1 =}";
+
+        let (output, errors) = render(template);
+        assert_eq!(output, "||||");
+        assert_eq!(errors, vec![
+            "mismatch types text(n=) PLUS int(5)",
+            "mismatch types text(4) STAR int(2)",
+            "mismatch types text(1.5) PLUS int(1)",
+            "Operator MINUS invalid for strings",
+            "mismatch types text(a) MINUS int(1)",
+        ]);
+
+        let mut amps = Amps::new();
+        amps.set_coercion(true);
+        amps.load_template(String::from(template));
+        let (output, errors) = render_with(&amps);
+        assert_eq!(output, "n=5|8|2.5|4|");
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(), vec!["mismatch types text(a) MINUS int(1)"]);
+    }
+
+    #[test]
+    fn is_tests() {
+        let mut amps = Amps::new();
+        amps.set("user", {
+            let mut user = ValueMap::new();
+            user.insert(String::from("name"), Value::from("Ann"));
+            user
+        });
+        amps.load_template(String::from(concat!(
+            "{= user is defined =} {= user.age is defined =} {= nobody is undefined =} {= nobody[0].x is defined =}|",
+            "{= \"\" is empty =} {= [0] is not empty =} {= null is empty =} {= 4 is even =} {= 3 is odd =}|",
+            "{= 1 is number =} {= 1.5 is integer =} {= 1.5 is float =} {= safe(\"a\") is string =} ",
+            "{= true is boolean =} {= [] is list =} {= {} is map =} {= null is null =} {= 1 is not null =}|",
+            "{= 1 + 1 is even =} {% if user.name is defined and user.name is not empty %}named{% endif %}")));
+        assert_eq!(amps.render_to_string().unwrap(),
+                   "true false true false|true true true true true|true false true true true true true true true|true named");
+
+        let (output, errors) = render("{= 1.5 is even =}|{= 1 is shiny =}|{= 1 is 2 =}|{= false and x is shiny =}");
+        assert_eq!(output, "null|null|null|false");
+        assert_eq!(errors, vec![
+            "test even is invalid for float(1.5)",
+            "unknown test shiny",
+            "is expects a test name, found Some(\n    Token {\n        token_type: NUMBER,\n        value: Some(\n            \"2\",\n        ),\n    },\n)",
+        ]);
+    }
+
+    #[test]
+    fn host_tests() {
+        let mut amps = Amps::new();
+        amps.add_test("positive", |v| matches!(v, Value::Int(n) if *n > 0));
+        amps.add_test("even", |v| matches!(v, Value::Text(t) if t.len() % 2 == 0));
+        amps.load_template(String::from("{= 5 is positive =} {= -5 is not positive =} {= \"ab\" is even =} {= 3 is even =}"));
+        assert_eq!(amps.render_to_string().unwrap(), "true true true false");
+    }
+
+    #[test]
+    fn interpolation_holds_strings() {
+        let mut amps = Amps::new();
+        amps.set("orders", orders());
+        amps.load_template(String::from(concat!(
+            "{= \"Order ${ orders[0][\"id\"] }: ${ orders[0][\"items\"][0] }\" =}|",
+            "{= \"${ format(3.14159, \".2\") }\" =}|{= \"${ \"in${ \"ner\" }\" }\" =}|",
+            "{= \"${ {\"a\": \"}\"}[\"a\"] }\" =}|{= \"${ 2 }\":>3 =}|",
+            "{= \"cost \\${x} in $\" =}")));
+        assert_eq!(amps.render_to_string().unwrap(), "Order 7: pen|3.14|inner|}|  2|cost ${x} in $");
+
+        let (output, errors) = render("{= \"${ 1 + \" =}");
+        assert_eq!(output, "");
+        assert_eq!(errors, vec!["unclosed ${ in \"${ 1 + \""]);
+    }
+
+    #[test]
+    fn expressions_left_incomplete_or_with_extra_tokens() {
+        let (output, errors) = render("{= 1 + =}|{= 3 - =}");
+        assert_eq!(output, "|");
+        assert_eq!(errors, vec![
+            "unexpected token None",
+            "Operator PLUS invalid for int(1) null(null)",
+            "unexpected token None",
+            "Operator MINUS invalid for int(3) null(null)",
+        ]);
+
+        for template in &["{= 1 2 =}", "{= 1.5e3 =}", "{% print 1 2 %}"] {
+            let (output, errors) = render(template);
+            assert_eq!(output, "");
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].starts_with("unexpected token Some(") && errors[0].ends_with(" in print"),
+                    "{}: {:?}", template, errors);
+        }
+    }
+
+    #[test]
+    fn strict_and_lenient_truthiness() {
+        let template = concat!("{= not true =} {= ~5 =} {= not [] =} {= \"a\" if [1] else \"b\" =} ",
+                               "{% if 0 %}yes{% else %}no{% endif %} {= not 5 =}");
+
+        let mut amps = Amps::new();
+        amps.set_truthiness(Truthiness::Lenient);
+        amps.load_template(String::from(template));
+        assert_eq!(amps.render_to_string().unwrap(), "false -6 true a no false");
+
+        amps.set_truthiness(Truthiness::Strict);
+        let (output, errors) = render_with(&amps);
+        assert_eq!(output, "false -6 null b no null");
+        let errors: Vec<&str> = errors.iter().map(|e| e.message()).collect();
+        assert_eq!(errors, vec![
+            "invalid not list([])",
+            "inline if condition must evaluates to boolean, not list([1])",
+            "if expression must evaluates to boolean",
+            "invalid not int(5)",
+        ]);
+    }
+
+    #[test]
+    fn regular_expressions() {
+        let (output, errors) = render(concat!(
+            "{= \"ab12\" matches \"^[a-z]+[0-9]+$\" =} {= \"ab\" matches \"[0-9]\" =} ",
+            "{= regex_replace(\"a1b22\", \"[0-9]+\", \"#\") =} {= regex_captures(\"k=v\", \"(\\w)=(\\w)\") =} ",
+            "{= regex_captures(\"kv\", \"=\") =}"));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(output, "true false a#b# [\"k=v\", \"k\", \"v\"] []");
+
+        let (output, errors) = render("{= \"a\" matches \"(\" =}|{= regex_replace(\"a\", \"[\", \"\") =}");
+        assert_eq!(output, "null|null");
+        assert_eq!(errors, vec![
+            "invalid pattern \"(\" at line 1, column 1: unclosed group",
+            "regex_replace: invalid pattern \"[\" at line 1, column 1: unclosed character class",
+        ]);
+
+        // more patterns than the cache keeps
+        let (output, errors) = render(concat!(
+            "{% set i = 0 %}{% while i lt 200 %}",
+            "{% if not (\"x\" + str(i) matches \"^x\" + str(i) + \"$\") %}bad{% endif %}",
+            "{% set i = i + 1 %}{% endwhile %}{= i =}"));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(output, "200");
+    }
+
+    // writes every (name, source) in files to a new directory and returns it
+    fn template_dir(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("amps-{}-{}", test, process::id()));
+        for (name, source) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn extends_with_super() {
+        let dir = template_dir("extends", &[
+            ("base.tpl", "<{% block title %}Base{% endblock %}|{% block body %}B{% endblock %}>"),
+            ("pages/mid.tpl", "{% extends \"base.tpl\" %}{% block title %}Mid-{= super() =}{% endblock %}"),
+            ("pages/page.tpl", concat!("{% extends \"pages/mid.tpl\" %}ignored",
+                                       "{% block title %}Page-{= super() =}{% endblock %}")),
+            ("twice.tpl", concat!("{% extends \"base.tpl\" %}{% block body %}1{% endblock %}",
+                                  "{% block body %}2{% endblock %}")),
+            ("cycle.tpl", "{% extends \"cycle.tpl\" %}"),
+        ]);
+
+        let mut amps = Amps::new();
+        amps.set_template_root(&dir);
+        amps.load_template_from_file("pages/page.tpl");
+        assert!(amps.get_errors().is_empty());
+        assert_eq!(amps.render_to_string().unwrap(), "<Page-Mid-Base|B>");
+
+        amps.load_template_from_file("twice.tpl");
+        let (output, errors) = render_with(&amps);
+        assert_eq!(output, "<Base|1>");
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(), vec!["block body defined twice"]);
+
+        amps.load_template_from_file("cycle.tpl");
+        let (_, errors) = render_with(&amps);
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(),
+                   vec!["template \"cycle.tpl\" is extended in a cycle"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn blocks_defined_twice() {
+        let (output, errors) = render("{% block a %}A{% endblock %}{% block a %}B{% endblock %}");
+        assert_eq!(output, "AB");
+        assert_eq!(errors, vec!["block a defined twice"]);
+    }
+
+    #[test]
+    fn templates_are_found_inside_the_root() {
+        let dir = template_dir("root", &[
+            ("forms.tpl", "{% macro field(name) %}[{= name =}]{% endmacro %}"),
+            ("page.tpl", "{% import \"forms.tpl\" %}{= field(\"a\") =}"),
+        ]);
+
+        let mut amps = Amps::new();
+        amps.set_template_root(&dir);
+        amps.load_template_from_file("page.tpl");
+        assert_eq!(amps.render_to_string().unwrap(), "[a]");
+
+        for name in &["../page.tpl", "/etc/passwd"] {
+            amps.load_template(format!("{{% import \"{}\" %}}", name));
+            let (_, errors) = render_with(&amps);
+            assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(),
+                       vec![format!("template \"{}\" is outside the template root", name)]);
+        }
+
+        amps.load_template(String::from("{% import \"missing.tpl\" %}"));
+        let (_, errors) = render_with(&amps);
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(),
+                   vec!["template \"missing.tpl\" not found"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn macro_arguments() {
+        let (output, errors) = render(concat!(
+            "{% macro hi(name, greeting=\"Hi\") %}{= greeting =} {= name =}{% endmacro %}",
+            "{= hi(\"Ann\") =}|{= hi(\"Bo\", \"Yo\") =}|{= hi() =}|{= hi(1, 2, 3) =}|{= nope() =}"));
+        assert_eq!(output, "Hi Ann|Yo Bo|null|null|null");
+        assert_eq!(errors, vec![
+            "macro hi missing argument name",
+            "macro hi takes 2 arguments but 3 were given",
+            "unknown function nope",
+        ]);
+    }
+
+    #[test]
+    fn macro_depth_is_configurable() {
+        let template = concat!("{% macro r(n) %}{% if n gt 0 %}{= r(n - 1) =}{% else %}done{% endif %}{% endmacro %}",
+                               "{= r(20) =}");
+        let (_, errors) = render(template);
+        assert_eq!(errors, vec!["macro r nested more than 16 calls deep"]);
+
+        let mut amps = Amps::new();
+        amps.set_max_macro_depth(24);
+        amps.load_template(String::from(template));
+        assert_eq!(amps.render_to_string().unwrap(), "done");
+
+        amps.set_max_macro_depth(4);
+        let (_, errors) = render_with(&amps);
+        assert_eq!(errors.iter().map(|e| e.message()).collect::<Vec<_>>(), vec!["macro r nested more than 4 calls deep"]);
+    }
+
+    #[test]
+    fn nested_if_elif_else() {
+        let template = concat!("{% if a %}{% if b %}AB{% elif c %}AC{% else %}A-{% endif %}",
+                               "{% elif c %}C{% else %}-{% endif %}");
+        let cases = [
+            (true, true, false, "AB"),
+            (true, false, true, "AC"),
+            (true, false, false, "A-"),
+            (false, true, true, "C"),
+            (false, true, false, "-"),
+        ];
+
+        for &(a, b, c, expected) in &cases {
+            let mut amps = Amps::new();
+            amps.extend(vec![("a", a), ("b", b), ("c", c)]);
+            amps.load_template(template.to_string());
+            assert_eq!(amps.render_to_string().unwrap(), expected, "a={} b={} c={}", a, b, c);
+        }
+
+        let (output, errors) = render("{% if false %}{% if true %}x{% else %}y{% endif %}z{% else %}w{% endif %}");
+        assert_eq!(output, "w");
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn mismatched_blocks() {
+        let cases = [
+            ("{% endif %}", "mismatch endif at line 1, there is no open block"),
+            ("{% if true %}{% endwhile %}{% endif %}", "mismatch endwhile at line 1, if started at line 1 is still open"),
+            ("{% if true %}{% else %}{% else %}{% endif %}", "else at line 1 after else of the if started at line 1"),
+            ("{% break %}", "break at line 1 outside while"),
+        ];
+
+        for &(template, expected) in &cases {
+            assert_eq!(render(template).1, vec![expected], "{}", template);
+        }
+    }
+
+    #[test]
+    fn unclosed_blocks() {
+        let mut amps = Amps::new();
+        amps.load_template(String::from("{% if true %}\nopen\n"));
+        let (output, errors) = render_with(&amps);
+        assert_eq!(output, "\nopen\n");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "unclosed if started at line 1");
+        assert_eq!(errors[0].span().map(|s| (s.line, s.column)), Some((1, 1)));
+    }
+
+    #[test]
+    fn with_scopes() {
+        let mut amps = Amps::new();
+        amps.extend(vec![("price", 3), ("qty", 4), ("total", 1)]);
+        amps.load_template(String::from(concat!(
+            "{% with total = price * qty, twice = total * 2 %}{= total =} {= twice =}{% endwith %} {= total =}",
+            "{% if false %}{% with total = 0 %}{% endwith %}{% endif %}{= total =}")));
+        assert_eq!(amps.render_to_string().unwrap(), "12 24 11");
+
+        let (output, errors) = render("{% with t = 1 %}{% endwith %}{= t =}");
+        assert_eq!(output, "null");
+        assert_eq!(errors, vec!["invalid identifier t"]);
+
+        // the scope of the with goes away with the macro call
+        let (output, errors) = render("{% macro m(secret) %}{% with x = 1 %}{% endmacro %}{= m(\"leak\") =}{= secret =}");
+        assert_eq!(output, "null");
+        assert_eq!(errors, vec!["unclosed with started at line 1", "invalid identifier secret"]);
+    }
+
+    #[test]
+    fn while_loops() {
+        let (output, errors) = render("{% set i = 0 %}{% while i lt 3 %}{= i =}{% set i = i + 1 %}{% endwhile %}");
+        assert_eq!(output, "012");
+        assert!(errors.is_empty());
+
+        let template = concat!("{% set n = 0 %}{% while true %}{% set n = n + 1 %}",
+                               "{% if n eq 2 %}{% continue %}{% endif %}",
+                               "{% if n gt 4 %}{% break %}{% endif %}",
+                               "{= n =}{% endwhile %}");
+        let (output, errors) = render(template);
+        assert_eq!(output, "134");
+        assert!(errors.is_empty());
+
+        let mut amps = Amps::new();
+        amps.set_max_iterations(5);
+        amps.load_template(String::from("{% set i = 0 %}\n{% while true %}{% set i = i + 1 %}{% endwhile %}{= i =}"));
+        let (output, errors) = render_with(&amps);
+        assert_eq!(output, "\n5");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message(), "while loop at line 2 exceeded the maximum of 5 iterations");
+    }
+
+    #[test]
+    fn set_stays_in_its_scope() {
+        // a with variable is changed until endwith, like a new name bound
+        // in the with block
+        let template = "{% set n = 1 %}{% with w = 1 %}{% set w = 2 %}{% set n = 3 %}{% set m = 4 %}{= w =}{% endwith %}{= n =}{= m =}";
+        assert_eq!(render(template), (String::from("23null"), vec![String::from("invalid identifier m")]));
+
+        // a macro changes its own variables, never the caller's
+        let template = "{% macro m() %}{% set n = 99 %}{= n =}{% endmacro %}{% set n = 1 %}{= m() =}{= n =}";
+        assert_eq!(render(template), (String::from("991"), Vec::new()));
+
+        let mut amps = Amps::new();
+        amps.set("n", 1);
+        amps.load_template(String::from("{% set n = n + 1 %}{= n =}{% set n = 1 / 0 %}{= n =}"));
+        let (output, errors) = render_with(&amps);
+        assert_eq!(output, "22");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(amps.get("n"), Some(&Value::Int(1)));
+    }
+
+    fn render_html(template: &str) -> String {
+        let mut amps = Amps::new();
+        amps.set_autoescape(Escape::Html);
+        amps.set("evil", "<script>&'\"");
+        amps.load_template(template.to_string());
+        let (output, errors) = render_with(&amps);
+        assert!(errors.is_empty(), "{:?}", errors);
+        output
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(render_html("{= evil =}"), "&lt;script&gt;&amp;&#x27;&quot;");
+        assert_eq!(render_html("{= safe(evil) =}"), "<script>&'\"");
+
+        // safe() in a branch not taken trusts nothing
+        assert_eq!(render_html("{= \"x\" if true else safe(evil) =} {= evil =}"),
+                   "x &lt;script&gt;&amp;&#x27;&quot;");
+
+        // a text trusted in html isn't trusted by its content in json
+        assert_eq!(render_html("{= safe(evil) =}{% autoescape json %}{= evil =}{% endautoescape %}"),
+                   "<script>&'\"<script>&'\\\"");
+
+        // a macro output is escaped once, laid out or not
+        let template = "{% macro b(t) %}<b>{= t =}</b>{% endmacro %}[{= b(\"&\"):<20 =}]{= b(evil) =}";
+        assert_eq!(render_html(template),
+                   "[<b>&amp;</b>        ]<b>&lt;script&gt;&amp;&#x27;&quot;</b>");
+
+        // what is computed from a safe text is a plain text
+        assert_eq!(render_html("{% set s = safe(\"<i>\") %}{= s =}{= s + \"<\" =}{= s[0] =}"),
+                   "<i>&lt;i&gt;&lt;&lt;");
+    }
+
+    #[test]
+    fn autoescape_blocks() {
+        assert_eq!(render_html("{% autoescape off %}{= evil =}{% endautoescape %}{= evil =}"),
+                   "<script>&'\"&lt;script&gt;&amp;&#x27;&quot;");
+        assert_eq!(render_html("{% autoescape shell %}echo {= evil =}{% endautoescape %}"),
+                   "echo '<script>&'\\''\"'");
+
+        // text written in the template is never escaped
+        assert_eq!(render_html("<p>{= \"a<b\" =}</p>"), "<p>a&lt;b</p>");
+
+        let (_, errors) = render("{% autoescape yaml %}{% endautoescape %}");
+        assert_eq!(errors, vec!["unknown autoescape yaml, expected off, html, xml, json or shell"]);
+    }
+
+    #[test]
+    fn escape_follows_the_template_loaded() {
+        let mut amps = Amps::new();
+        amps.set("evil", "<");
+        amps.load_template_from_file("/missing/template.html");
+        amps.load_template(String::from("{= evil =}"));
+        assert_eq!(amps.render_to_string().unwrap(), "<");
+
+        let path = std::env::temp_dir().join(format!("amps-escape-{}.html", std::process::id()));
+        std::fs::write(&path, "{= evil =}").unwrap();
+        amps.load_template_from_file(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(amps.render_to_string().unwrap(), "&lt;");
+    }
+
+    #[test]
+    fn equality_binds_tighter_than_and_or() {
+        let mut amps = Amps::new();
+        amps.extend(vec![("n", Value::from(5)), ("name", Value::from("Bob"))]);
+        amps.load_template(String::from(concat!(
+            "{= n eq 5 and name eq \"Bob\" =} {= n ne 5 or name ne \"Ann\" =} ",
+            "{= 1 + 1 eq 2 =} {= 1 le n eq 5 =} {= n eq 5 eq 6 =}")));
+        assert_eq!(amps.render_to_string().unwrap(), "true true true true false");
+    }
+
+    #[test]
+    fn chained_comparisons() {
+        let template = "{% if 1 le x le 10 %}in{% else %}out{% endif %}";
+        for (x, expected) in [(0, "out"), (1, "in"), (10, "in"), (11, "out")] {
+            let mut amps = Amps::new();
+            amps.set("x", x);
+            amps.load_template(String::from(template));
+            assert_eq!(amps.render_to_string().unwrap(), expected);
+        }
+
+        let (output, errors) = render(concat!(
+            "{= 1 lt 2 lt 3 =} {= 3 gt 2 gt 1 =} {= 1 lt 3 lt 2 =} {= 1 lt 2 eq 2 =} {= (1 lt 2) eq true =} ",
+            "{= \"a\" in \"abc\" in [\"abc\"] =} {= 1 eq 1.0 ne 2 =} {= 2 lt 1 lt 1 // 0 =}"));
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(output, "true true false true true true true false");
+
+        let (output, errors) = render("{= 1 lt \"a\" lt 1 // 0 =}");
+        assert_eq!(output, "null");
+        assert_eq!(errors, vec!["mismatch types int(1) LT text(a)"]);
+    }
+
+    #[test]
+    fn and_or_short_circuit() {
+        let template = concat!("{% if zz is defined and zz gt 1 %}big{% else %}none{% endif %} ",
+                               "{= zz is undefined or zz gt 1 =} {= true and 1 eq 1 =} {= false or false =} ",
+                               "{= false and regex_replace(\"a\", \"(\", \"\") =}");
+        assert_eq!(render(template), (String::from("none true true false false"), Vec::new()));
+
+        let mut amps = Amps::new();
+        amps.add_test("counted", |_| panic!("the right operand was evaluated"));
+        amps.load_template(String::from("{= true or 1 is counted =}{= false and 1 is counted =}"));
+        assert_eq!(amps.render_to_string().unwrap(), "truefalse");
+    }
+
+    #[test]
+    fn errors_name_the_template_they_are_in() {
+        let path = std::env::temp_dir().join(format!("amps-forms-{}.tpl", std::process::id()));
+        let name = path.to_str().unwrap().to_string();
+        std::fs::write(&path, "{% macro f() %}\n{= missing =}{% endmacro %}").unwrap();
+
+        let mut amps = Amps::new();
+        amps.load_template(format!("{{% import \"{}\" %}}{{= f() =}}", name));
+        let (_, errors) = render_with(&amps);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].template(), Some(name.as_str()));
+        assert_eq!(errors[0].span().map(|s| s.line), Some(2));
+        assert!(errors[0].to_string().contains(&format!("--> {}, line 2, column 1", name)));
+    }
+
+    // renders template on a thread with the stack std::thread::spawn gives by
+    // default, returns the output and the error messages
+    fn render_on_thread(template: String) -> (String, Vec<String>) {
+        let handle = thread::Builder::new()
+            .stack_size(2 * 1024 * 1024)
+            .spawn(move || {
+                let mut amps = Amps::new();
+                amps.load_template(template);
+                match amps.render_to_string() {
+                    Ok(output) => (output, Vec::new()),
+                    Err(errors) => (String::new(), errors.iter().map(|e| e.message().to_string()).collect()),
+                }
+            })
+            .unwrap();
+
+        handle.join().expect("rendering overflowed the stack")
+    }
+
+    #[test]
+    fn deep_expressions_fit_the_stack() {
+        let (output, errors) = render_on_thread(format!("{{= {}1{} =}}", "(".repeat(62), ")".repeat(62)));
+        assert_eq!(output, "1");
+        assert!(errors.is_empty());
+
+        for template in &[
+            format!("{{= {}1{} =}}", "(".repeat(1000), ")".repeat(1000)),
+            format!("{{= {}1 =}}", "- ".repeat(1000)),
+            format!("{{= {}1{} =}}", "[".repeat(1000), "]".repeat(1000)),
+        ] {
+            let (_, errors) = render_on_thread(template.clone());
+            assert_eq!(errors, vec!["expression nested more than 64 levels deep"]);
+        }
+    }
+
+    #[test]
+    fn deep_macro_calls_fit_the_stack() {
+        let (_, errors) = render_on_thread(String::from("{% macro r() %}{= r() =}{% endmacro %}{= r() =}"));
+        assert_eq!(errors, vec!["macro r nested more than 16 calls deep"]);
+
+        let template = format!("{{% macro r(k) %}}{{= r({}k{}) =}}{{% endmacro %}}{{= r(1) =}}",
+                               "(".repeat(150), ")".repeat(150));
+        let (_, errors) = render_on_thread(template);
+        assert_eq!(errors, vec!["expression nested more than 64 levels deep"]);
+
+        let template = format!("{{% macro r(k) %}}{{% if k gt 0 %}}{{= {}r(k - 1){} =}}{{% endif %}}{{% endmacro %}}{{= r(100) =}}",
+                               "(".repeat(10), ")".repeat(10));
+        let (_, errors) = render_on_thread(template);
+        assert_eq!(errors, vec!["expression nested more than 64 levels deep"]);
+    }
+}
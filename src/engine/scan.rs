@@ -1,7 +1,9 @@
 pub mod scanner {
+    use std::sync::Arc;
+
     use regex::Regex;
-    use std::io::BufRead;
 
+    use crate::engine::error::{Source, Span};
     use crate::engine::metadata;
     use crate::engine::token_types;
     use crate::engine::token;
@@ -16,42 +18,51 @@ pub mod scanner {
         }
     }
 
+    // a string literal, where the expressions embedded with ${...} can hold
+    // string literals of their own
+    macro_rules! string_literal {
+        () => { r#""(?:[^"$]|\$\{(?:[^"}]|"[^"]*")*\}|\$)*""# }
+    }
+
     // implements the regular expression responsible to look for {= .* =} and
     // {% .* %}. Each of these two blocks will be evaluated, anything else is
-    // just text.
-    const REG_BLOCK: &str = concat!(r#"(?P<code>\{% [a-z][a-zA-Z0-9*\-,.%_\\\[\]"()+/ ]+ %\})|"#,
-                                     r#"(?P<echo>\{= [a-z0-9"\-][a-zA-Z0-9*\-,.%_\\\[\]"()+/ ]+ =\})|"#,
-                                     r#"(?P<text>.[^\{]*)"#);
+    // just text. Blocks are matched lazily because braces are valid inside
+    // them (map literals), so a block ends at the first closing %} or =}.
+    // String literals inside a block can hold any character. Text keeps the
+    // line break that ends it.
+    const REG_BLOCK: &str = concat!(r#"(?P<code>\{% [a-z](?:"#, string_literal!(), r#"|[a-zA-Z0-9*\-,.:%_\\\[\]{}()+/=&|^~<> ])+? %\})|"#,
+                                     r#"(?P<echo>\{= (?:"#, string_literal!(), r#"|[a-z0-9\-\[{(~])(?:"#, string_literal!(),
+                                     r#"|[a-zA-Z0-9*\-,.:%_\\\[\]{}()+/&|^~<># ])*? =\})|"#,
+                                     r#"(?P<text>(?s:.)[^\{]*)"#);
 
     const REG_INNER_BLOCK: &str = r"^\{[%|=] (?P<code>.+) [%|=]\}";
 
-    pub fn scan_old(file: &mut std::io::BufReader<std::fs::File>) -> metadata::Metainfo {
-        let mut ret: metadata::Metainfo = Vec::new();
-
-        for line in file.lines() {
-            let mut data = parse_block(&line.unwrap());
-            ret.append(&mut data);
-        }
-
-        ret
-    }
-
-    pub fn scan(template: &String) -> metadata::Metainfo {
+    // scan
+    //
+    // Splits template into metadata, line by line. Every byte of the
+    // template outside blocks is kept as text, line breaks included. name
+    // is what the template was loaded by, errors found in it show it.
+    pub fn scan(template: &str, name: Option<&str>) -> metadata::Metainfo {
         let mut ret: metadata::Metainfo = Vec::new();
+        let name: Option<Arc<str>> = name.map(Arc::from);
 
-        for line in template.lines() {
-            let mut data = parse_block(&line.to_string());
+        for (number, line) in template.split_inclusive('\n').enumerate() {
+            let mut data = parse_block(line, number + 1, &name);
             ret.append(&mut data);
         }
 
         ret
     }
 
-    fn parse_block(line: &String) -> metadata::Metainfo {
+    fn parse_block(line: &str, number: usize, name: &Option<Arc<str>>) -> metadata::Metainfo {
         let re_block = Regex::new(REG_BLOCK).unwrap();
         let mut ret: metadata::Metainfo = Vec::new();
+        let source = Source {
+            template: name.clone(),
+            line: Arc::from(line.trim_end_matches(&['\n', '\r'][..])),
+        };
 
-        for group in re_block.captures_iter(&line) {
+        for group in re_block.captures_iter(line) {
             for name in re_block.capture_names() {
                 match name {
                     Some(s) => match group.name(s) {
@@ -60,15 +71,13 @@ pub mod scanner {
                             let mtype: metadata::Metatype;
 
                             // text is anything outside a block {% %} or {= =}, they are not
-                            // evaluated and are simply printed as is
+                            // evaluated and are simply printed as is, never escaped
                             if s == "text" {
-                                mtype = metadata::Metatype::ECHO;
+                                mtype = metadata::Metatype::TEXT;
 
-                                let print_tk = token::Token::new(token_types::TokenTypes::PRINT,
-                                                                 Some(String::from("print")));
-                                let string_tk = token::Token::new(token_types::TokenTypes::STRING,
-                                                                  Some(String::from(gs.as_str())));
-                                tokens = Some(vec![print_tk, string_tk]);
+                                let text_tk = token::Token::new(token_types::TokenTypes::TEXT,
+                                                                Some(String::from(gs.as_str())));
+                                tokens = Some(vec![text_tk]);
                             }
 
                             // code is a block inside {% %}, there must one and only one
@@ -76,7 +85,10 @@ pub mod scanner {
                             else if s == "code" {
                                 let data = String::from(gs.as_str());
                                 mtype = metadata::Metatype::CODE;
-                                tokens = Some(tokenize(&data));
+                                tokens = match tokenize(&data, false) {
+                                    Ok(tks) => Some(tks),
+                                    Err(e) => Some(vec![invalid_token(e)]),
+                                };
                             }
 
                             // echo is a block inside {= =}, it behaves like texts but the block
@@ -87,9 +99,10 @@ pub mod scanner {
 
                                 let print_tk = token::Token::new(token_types::TokenTypes::PRINT,
                                                                  Some(String::from("print")));
-                                let mut vec = vec![print_tk];
-                                vec.extend(tokenize(&data));
-                                tokens = Some(vec);
+                                tokens = match tokenize(&data, true) {
+                                    Ok(tks) => Some(std::iter::once(print_tk).chain(tks).collect()),
+                                    Err(e) => Some(vec![invalid_token(e)]),
+                                };
                             }
 
                             // anything else is error
@@ -98,10 +111,17 @@ pub mod scanner {
                                 tokens = None;
                             }
 
+                            let span = Span {
+                                line: number,
+                                column: line[..gs.start()].chars().count() + 1,
+                                length: gs.as_str().trim_end_matches(&['\n', '\r'][..]).chars().count(),
+                            };
                             ret.push(metadata::Metadata::new(
                                 mtype,
                                 String::from(gs.as_str()),
                                 tokens,
+                                span,
+                                source.clone(),
                             ));
                         }
                         None => continue,
@@ -114,17 +134,72 @@ pub mod scanner {
         ret
     }
 
-    fn tokenize(code: &String) -> Vec<token::Token> {
-        let mut ret: Vec<token::Token> = Vec::new();
+    // tokenize
+    //
+    // Splits the code of a block into tokens. An echo block can end with a
+    // format spec, {= price:>10.2 =}, which is kept as a single FORMAT token
+    fn tokenize(code: &String, echo: bool) -> Result<Vec<token::Token>, String> {
         let re_internal = Regex::new(REG_INNER_BLOCK).unwrap();
-        let mut iter = match re_internal.captures(code) {
-            Some(cap) => match cap.name("code") {
-                Some(name) => name.as_str().chars().peekable(),
-                None => panic!("cannot retrieve code from block {}", code),
-            }
-            None => panic!("invalid code {}", code),
+        let inner = match re_internal.captures(code).and_then(|cap| cap.name("code")) {
+            Some(name) => name.as_str(),
+            None => return Err(format!("invalid block {}", code)),
         };
 
+        if !echo {
+            return tokenize_expression(inner).map_err(|e| format!("{} in {}", e, code));
+        }
+
+        let (expression, spec) = split_format_spec(inner);
+        let mut tokens = tokenize_expression(expression).map_err(|e| format!("{} in {}", e, code))?;
+        if let Some(spec) = spec {
+            tokens.push(token::Token::new(token_types::TokenTypes::FORMAT,
+                                          Some(spec.to_string())));
+        }
+        Ok(tokens)
+    }
+
+    // a block that can't be tokenized becomes a single INVALID token holding
+    // the reason, it's reported when the block is run
+    fn invalid_token(reason: String) -> token::Token {
+        token::Token::new(token_types::TokenTypes::INVALID, Some(reason))
+    }
+
+    // split_format_spec
+    //
+    // A : outside strings and brackets can only start a format spec, so the
+    // expression ends right before the last one
+    fn split_format_spec(code: &str) -> (&str, Option<&str>) {
+        let mut depth: usize = 0;
+        let mut found = None;
+        let mut chars = code.char_indices();
+
+        while let Some((at, ch)) = chars.next() {
+            match ch {
+                '"' => match string_end(&code[at + 1..]) {
+                    Some(end) => skip_to(&mut chars, at + 1 + end),
+                    None => break,
+                },
+                '(' | '[' | '{' => depth += 1,
+                ')' | ']' | '}' => depth = depth.saturating_sub(1),
+                ':' if depth == 0 => found = Some(at),
+                _ => (),
+            }
+        }
+
+        match found {
+            Some(at) => (&code[..at], Some(code[at + 1..].trim_end())),
+            None => (code, None),
+        }
+    }
+
+    // tokenize_expression
+    //
+    // Splits the code of a block, or an expression embedded in a string, into
+    // tokens
+    pub fn tokenize_expression(code: &str) -> Result<Vec<token::Token>, String> {
+        let mut ret: Vec<token::Token> = Vec::new();
+        let mut iter = code.chars().peekable();
+
         loop {
             match iter.peek() {
                 None => break,
@@ -149,85 +224,142 @@ pub mod scanner {
                         }
                         // operators and errors
                         _ => {
-                            let oper = parse_single_op(ch);
-                            if oper.is_err() {
-                                break;
+                            iter.next();
+                            let double = match iter.peek() {
+                                Some(&next) => parse_double_op(ch, next),
+                                None => None,
+                            };
+
+                            if let Some(oper) = double {
+                                ret.push(oper);
+                                iter.next();
+                                continue;
                             }
 
-                            ret.push(oper.unwrap());
-                            iter.next();
+                            ret.push(parse_single_op(ch)?);
                         }
                     }
                 }
             }
         }
 
-        ret
+        Ok(ret)
     }
 
+    // parse_string
+    //
+    // Reads a string literal up to its closing ", which is left for the
+    // caller to skip
     fn parse_string<P>(iter: &mut P) -> token::Token
-    where P: PeekableIterator<Item=char> {
-        let mut data: String = String::new();
-
+    where P: PeekableIterator<Item=char> + Clone {
         assert!(iter.peek() == Some(&'"'));
         iter.next();
 
-        loop {
-            match iter.peek() {
-                None => break,
-                Some(&ch) => {
-                    match ch {
-                        '"' => break,
-                        _ => {
-                            data.push(ch);
-                        }
+        let rest: String = iter.clone().collect();
+        let length = match string_end(&rest) {
+            Some(end) => rest[..end].chars().count(),
+            None => rest.chars().count(),
+        };
+        let data: String = iter.take(length).collect();
+
+        // strings embedding expressions, like "${name}", are evaluated by the
+        // parser every time they are used
+        if data.contains("${") {
+            token::Token::new(token_types::TokenTypes::INTERPOLATION, Some(data))
+        }
+        else {
+            token::Token::new(token_types::TokenTypes::STRING, Some(data))
+        }
+    }
+
+    // string_end
+    //
+    // Returns where the string literal in text ends, text starting right
+    // after its opening ". The expressions it embeds with ${...} can hold
+    // strings of their own, their quotes don't end it. \${ is a literal ${.
+    pub fn string_end(text: &str) -> Option<usize> {
+        let mut chars = text.char_indices();
+
+        while let Some((at, ch)) = chars.next() {
+            match ch {
+                '"' => return Some(at),
+                '\\' if text[at + 1..].starts_with("${") => skip_to(&mut chars, at + 2),
+                '$' if text[at + 1..].starts_with('{') => {
+                    // an unclosed ${ is left to the parser to report
+                    if let Some(end) = expression_end(&text[at + 2..]) {
+                        skip_to(&mut chars, at + 2 + end);
                     }
-                }
+                },
+                _ => (),
+            }
+        }
+
+        None
+    }
+
+    // expression_end
+    //
+    // Returns where the expression embedded in a string ends, at the }
+    // matching its ${. text starts right after the ${.
+    pub fn expression_end(text: &str) -> Option<usize> {
+        let mut depth: usize = 0;
+        let mut chars = text.char_indices();
+
+        while let Some((at, ch)) = chars.next() {
+            match ch {
+                '"' => skip_to(&mut chars, at + 1 + string_end(&text[at + 1..])?),
+                '{' => depth += 1,
+                '}' if depth == 0 => return Some(at),
+                '}' => depth -= 1,
+                _ => (),
             }
-            iter.next();
         }
 
-        token::Token::new(token_types::TokenTypes::STRING, Some(data))
+        None
     }
 
+    // consumes chars up to and including the one at stop
+    fn skip_to(chars: &mut std::str::CharIndices, stop: usize) {
+        for (at, _) in chars {
+            if at >= stop {
+                break;
+            }
+        }
+    }
+
+    // parse_number
+    // digits are kept as text, the parser decides whether it is an int
+    // or a float (when a '.' is found) and reports values out of range
     fn parse_number<P>(iter: &mut P) -> token::Token
     where P: PeekableIterator<Item=char> {
-        let mut data: u64 = 0;
-
-        loop {
-            match iter.peek() {
-                None => break,
-                Some(&ch) => {
-                    match ch {
-                        '0'..='9' => {
-                            let digit = ch as u64 - '0' as u64;
-                            data = data * 10 + digit;
-                        }
-                        _ => break,
-                    }
-                }
+        let mut data: String = String::new();
+        let mut found_dot = false;
+
+        while let Some(&ch) = iter.peek() {
+            match ch {
+                '0'..='9' => data.push(ch),
+                '.' if !found_dot => {
+                    found_dot = true;
+                    data.push(ch);
+                },
+                _ => break,
             }
             iter.next();
         }
 
-        token::Token::new(token_types::TokenTypes::NUMBER, Some(data.to_string()))
+        token::Token::new(token_types::TokenTypes::NUMBER, Some(data))
     }
 
     fn parse_id<P>(iter: &mut P) -> token::Token
     where P: PeekableIterator<Item=char> {
         let mut data: String = String::new();
 
-        loop {
-            match iter.peek() {
-                Some(&ch) => {
-                    match ch {
-                        'a'..='z' | 'A'..='Z' | '_' => {
-                            data.push(ch);
-                        }
-                        _ => break,
-                    }
-                },
-                None => break,
+        while let Some(&ch) = iter.peek() {
+            match ch {
+                'a'..='z' | 'A'..='Z' | '_' => {
+                    data.push(ch);
+                }
+                _ => break,
             }
             iter.next();
         }
@@ -242,20 +374,42 @@ pub mod scanner {
         }
     }
 
+    // operators made of two characters, like ** or <<
+    fn parse_double_op(first: char, second: char) -> Option<token::Token> {
+        let op_type = match (first, second) {
+            ('*', '*') => token_types::TokenTypes::DOUBLESTAR,
+            ('/', '/') => token_types::TokenTypes::DOUBLESLASH,
+            ('<', '<') => token_types::TokenTypes::LSHIFT,
+            ('>', '>') => token_types::TokenTypes::RSHIFT,
+            _ => return None,
+        };
+
+        let data: String = [first, second].iter().collect();
+        Some(token::Token::new(op_type, Some(data)))
+    }
+
     fn parse_single_op(op: char) -> Result<token::Token, String> {
         let op_type = match op {
             '+' => token_types::TokenTypes::PLUS,
             '-' => token_types::TokenTypes::MINUS,
             '/' => token_types::TokenTypes::SLASH,
             '%' => token_types::TokenTypes::PERCENT,
+            '&' => token_types::TokenTypes::AMPERSAND,
+            '|' => token_types::TokenTypes::PIPE,
+            '^' => token_types::TokenTypes::CARET,
+            '~' => token_types::TokenTypes::TILDE,
             '*' => token_types::TokenTypes::STAR,
             '=' => token_types::TokenTypes::ASSIGN,
             ',' => token_types::TokenTypes::COMMA,
+            '.' => token_types::TokenTypes::DOT,
             '(' => token_types::TokenTypes::LPAREN,
+            ':' => token_types::TokenTypes::COLON,
             '[' => token_types::TokenTypes::LBRACKET,
+            '{' => token_types::TokenTypes::LBRACE,
             ')' => token_types::TokenTypes::RPAREN,
             ']' => token_types::TokenTypes::RBRACKET,
-            _   => return Err(String::from(format!("invalid operator {}", op))),
+            '}' => token_types::TokenTypes::RBRACE,
+            _   => return Err(format!("invalid character '{}'", op)),
         };
 
         Ok(token::Token::new(op_type, Some(op.to_string())))
@@ -1,6 +1,7 @@
 use std::fmt;
 use super::token_types;
 
+#[derive(Clone)]
 pub struct Token {
     token_type: token_types::TokenTypes,
     value: Option<String>,
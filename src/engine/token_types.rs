@@ -1,11 +1,12 @@
 use phf::phf_map;
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenTypes {
     IDENTIFIER,
     VARIABLE,
     STRING,
+    INTERPOLATION,
     NUMBER,
     BOOLEAN,
     TRUE,
@@ -25,8 +26,17 @@ pub enum TokenTypes {
     SLASH,
     STAR,
     PERCENT,
+    DOUBLESTAR,
+    DOUBLESLASH,
+    AMPERSAND,
+    PIPE,
+    CARET,
+    TILDE,
+    LSHIFT,
+    RSHIFT,
     ASSIGN,
     COMMA,
+    DOT,
     IF,
     ELSE,
     ELIF,
@@ -34,14 +44,40 @@ pub enum TokenTypes {
     FOR,
     IN,
     ENDFOR,
+    COLON,
     LPAREN,
     LBRACKET,
+    LBRACE,
     RPAREN,
     RBRACKET,
+    RBRACE,
     RANGE,
     PRINT,
     EXCEPT,
     INSERT,
+    MACRO,
+    ENDMACRO,
+    IMPORT,
+    EXTENDS,
+    BLOCK,
+    ENDBLOCK,
+    WITH,
+    ENDWITH,
+    SET,
+    WHILE,
+    ENDWHILE,
+    BREAK,
+    CONTINUE,
+    MATCH,
+    CASE,
+    DEFAULT,
+    ENDMATCH,
+    IS,
+    FORMAT,
+    MATCHES,
+    TEXT,
+    AUTOESCAPE,
+    ENDAUTOESCAPE,
     INVALID,
 }
 
@@ -70,6 +106,27 @@ static KEYWORDS: phf::Map<&'static str, TokenTypes> = phf_map! {
     "print"     => TokenTypes::PRINT,
     "except"    => TokenTypes::EXCEPT,
     "insert"    => TokenTypes::INSERT,
+    "macro"     => TokenTypes::MACRO,
+    "endmacro"  => TokenTypes::ENDMACRO,
+    "import"    => TokenTypes::IMPORT,
+    "extends"   => TokenTypes::EXTENDS,
+    "block"     => TokenTypes::BLOCK,
+    "endblock"  => TokenTypes::ENDBLOCK,
+    "with"      => TokenTypes::WITH,
+    "endwith"   => TokenTypes::ENDWITH,
+    "set"       => TokenTypes::SET,
+    "while"     => TokenTypes::WHILE,
+    "endwhile"  => TokenTypes::ENDWHILE,
+    "break"     => TokenTypes::BREAK,
+    "continue"  => TokenTypes::CONTINUE,
+    "match"     => TokenTypes::MATCH,
+    "case"      => TokenTypes::CASE,
+    "default"   => TokenTypes::DEFAULT,
+    "endmatch"  => TokenTypes::ENDMATCH,
+    "is"        => TokenTypes::IS,
+    "matches"   => TokenTypes::MATCHES,
+    "autoescape"    => TokenTypes::AUTOESCAPE,
+    "endautoescape" => TokenTypes::ENDAUTOESCAPE,
 };
 
 pub fn keyword_by_token(token: &str) -> Option<TokenTypes> {
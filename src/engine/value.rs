@@ -0,0 +1,430 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+// Value is the only data representation used by the engine: everything pushed
+// onto the evaluation stack and everything stored in the environment is a
+// Value. Lists and maps hold Values themselves, so any nested structure can
+// be represented.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    // Safe is a text that is printed without being escaped, like the output
+    // of a macro, escaped already, or anything passed to safe(). It's a text
+    // everywhere else, and what is computed from it is a plain text again.
+    Safe(String),
+    List(Vec<Value>),
+    Map(ValueMap),
+}
+
+// ValueMap is a map that remembers the order its keys were inserted, so
+// iterating or printing it is deterministic and matches the host data. Keys
+// are indexed by their position in entries, so a lookup doesn't scan them.
+#[derive(Clone, PartialEq, Default)]
+pub struct ValueMap {
+    entries: Vec<(String, Value)>,
+    index: HashMap<String, usize>,
+}
+
+impl ValueMap {
+    pub fn new() -> ValueMap {
+        ValueMap {
+            entries: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.index.get(key).map(|&at| &self.entries[at].1)
+    }
+
+    // insert keeps the original position of a key that already exists and
+    // returns the value it replaced
+    pub fn insert(&mut self, key: String, value: Value) -> Option<Value> {
+        match self.index.get(&key) {
+            Some(&at) => Some(std::mem::replace(&mut self.entries[at].1, value)),
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, (String, Value)> {
+        self.entries.iter()
+    }
+}
+
+// the index is left out, it only mirrors entries
+impl fmt::Debug for ValueMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ValueMap")
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
+impl std::iter::FromIterator<(String, Value)> for ValueMap {
+    fn from_iter<T: IntoIterator<Item = (String, Value)>>(iter: T) -> ValueMap {
+        let mut map = ValueMap::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Int(n)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(n: i32) -> Value {
+        Value::Int(n as i64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Value {
+        Value::Float(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(t: &str) -> Value {
+        Value::Text(t.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(t: String) -> Value {
+        Value::Text(t)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(list: Vec<T>) -> Value {
+        Value::List(list.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<ValueMap> for Value {
+    fn from(map: ValueMap) -> Value {
+        Value::Map(map)
+    }
+}
+
+// None is null
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(data: Option<T>) -> Value {
+        match data {
+            Some(d) => d.into(),
+            None => Value::Null,
+        }
+    }
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null     => "null",
+            Value::Bool(_)  => "bool",
+            Value::Int(_)   => "int",
+            Value::Float(_) => "float",
+            Value::Text(_) | Value::Safe(_) => "text",
+            Value::List(_)  => "list",
+            Value::Map(_)   => "map",
+        }
+    }
+
+    // plain
+    //
+    // Returns a safe text as a plain one, any other value as it is
+    pub fn plain(self) -> Value {
+        match self {
+            Value::Safe(t) => Value::Text(t),
+            data => data,
+        }
+    }
+
+    // is_truthy
+    //
+    // false, 0, 0.0, an empty text, list or map and null are false, any
+    // other value is true
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Null      => false,
+            Value::Bool(b)   => *b,
+            Value::Int(n)    => *n != 0,
+            Value::Float(n)  => *n != 0.0,
+            Value::Text(t) | Value::Safe(t) => !t.is_empty(),
+            Value::List(l)   => !l.is_empty(),
+            Value::Map(m)    => m.iter().next().is_some(),
+        }
+    }
+
+    // index
+    // list[int], text[int] or map[text]
+    //
+    // Returns the element addressed by id, borrowed from lists and maps.
+    // Negative indexes count from the end of lists and texts, texts are
+    // indexed by character.
+    pub fn index(&self, id: &Value) -> Result<Cow<'_, Value>, String> {
+        match (self, id) {
+            (Value::List(list), Value::Int(i)) => {
+                match resolve_index(*i, list.len()) {
+                    Some(at) => Ok(Cow::Borrowed(&list[at])),
+                    None => Err(format!("index {} out of range for list of length {}", i, list.len())),
+                }
+            },
+            (Value::Text(text), Value::Int(i)) | (Value::Safe(text), Value::Int(i)) => {
+                let length = text.chars().count();
+                match resolve_index(*i, length).and_then(|at| text.chars().nth(at)) {
+                    Some(ch) => Ok(Cow::Owned(Value::Text(ch.to_string()))),
+                    None => Err(format!("index {} out of range for text of length {}", i, length)),
+                }
+            },
+            (Value::Map(map), Value::Text(key)) | (Value::Map(map), Value::Safe(key)) => {
+                match map.get(key) {
+                    Some(v) => Ok(Cow::Borrowed(v)),
+                    None    => Err(format!("key \"{}\" not found", key)),
+                }
+            },
+            (Value::List(_), _) | (Value::Text(_), _) | (Value::Safe(_), _) => {
+                Err(format!("{} index must be int, not {}", self.type_name(), id.type_name()))
+            },
+            (Value::Map(_), _) => Err(format!("map key must be text, not {}", id.type_name())),
+            _ => Err(format!("{} cannot be indexed", self.type_name())),
+        }
+    }
+}
+
+impl Value {
+    // slice
+    // list[start:stop:step] or text[start:stop:step]
+    //
+    // Returns the elements from start up to, but not including, stop taking
+    // every step-th one, with the same rules as Python slices: bounds can be
+    // omitted or negative and are clamped to the length, a negative step
+    // walks backwards
+    pub fn slice(&self, start: Option<i64>, stop: Option<i64>, step: Option<i64>) -> Result<Value, String> {
+        let step = step.unwrap_or(1);
+        if step == 0 {
+            return Err(String::from("slice step cannot be zero"));
+        }
+
+        match self {
+            Value::List(list) => {
+                let indexes = slice_indexes(list.len(), start, stop, step);
+                Ok(Value::List(indexes.into_iter().map(|i| list[i].clone()).collect()))
+            },
+            Value::Text(text) | Value::Safe(text) => {
+                let chars: Vec<char> = text.chars().collect();
+                let indexes = slice_indexes(chars.len(), start, stop, step);
+                Ok(Value::Text(indexes.into_iter().map(|i| chars[i]).collect()))
+            },
+            _ => Err(format!("{} cannot be sliced", self.type_name())),
+        }
+    }
+
+    // contains
+    // item in list, key in map or text in text
+    //
+    // Element membership for lists, key membership for maps and substring
+    // search for texts
+    pub fn contains(&self, item: &Value) -> Result<bool, String> {
+        match (self, item) {
+            (Value::List(list), _) => Ok(list.contains(item)),
+            (Value::Map(map), Value::Text(key)) | (Value::Map(map), Value::Safe(key)) => Ok(map.contains_key(key)),
+            (Value::Text(text), Value::Text(sub)) | (Value::Text(text), Value::Safe(sub)) |
+            (Value::Safe(text), Value::Text(sub)) | (Value::Safe(text), Value::Safe(sub)) => {
+                Ok(text.contains(sub.as_str()))
+            },
+            (Value::Map(_), _) => Err(format!("map key must be text, not {}", item.type_name())),
+            (Value::Text(_), _) | (Value::Safe(_), _) => Err(format!("cannot search {} in text", item.type_name())),
+            _ => Err(format!("{} is not a container", self.type_name())),
+        }
+    }
+
+    // attribute
+    // map.name
+    //
+    // Returns the map entry called name, None for anything that is not a
+    // map or doesn't have that key
+    pub fn attribute(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Map(map) => map.get(name),
+            _ => None,
+        }
+    }
+}
+
+// a safe text is equal to the plain text with the same content
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Null, Value::Null)         => true,
+            (Value::Bool(a), Value::Bool(b))   => a == b,
+            (Value::Int(a), Value::Int(b))     => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Text(a), Value::Text(b)) | (Value::Text(a), Value::Safe(b)) |
+            (Value::Safe(a), Value::Text(b)) | (Value::Safe(a), Value::Safe(b)) => a == b,
+            (Value::List(a), Value::List(b))   => a == b,
+            (Value::Map(a), Value::Map(b))     => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null     => write!(f, "null"),
+            Value::Bool(b)  => write!(f, "{}", b),
+            Value::Int(n)   => write!(f, "{}", n),
+            Value::Float(n) => write!(f, "{:?}", n),
+            Value::Text(t) | Value::Safe(t) => write!(f, "{}", t),
+            Value::List(list) => {
+                write!(f, "[")?;
+                for (i, v) in list.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write_nested(f, v)?;
+                }
+                write!(f, "]")
+            },
+            Value::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{:?}: ", k)?;
+                    write_nested(f, v)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+// turns a possibly negative index into a position inside a sequence of
+// length elements, None when it's out of range
+fn resolve_index(index: i64, length: usize) -> Option<usize> {
+    let length = length as i64;
+    let at = if index < 0 { index + length } else { index };
+
+    if at < 0 || at >= length {
+        None
+    }
+    else {
+        Some(at as usize)
+    }
+}
+
+// returns the positions selected by a slice over a sequence of length
+// elements, every bound is clamped so the positions are always valid
+fn slice_indexes(length: usize, start: Option<i64>, stop: Option<i64>, step: i64) -> Vec<usize> {
+    let length = length as i64;
+    let clamp = |bound: i64, low: i64, high: i64| {
+        let bound = if bound < 0 { bound + length } else { bound };
+        bound.max(low).min(high)
+    };
+
+    let mut ret = Vec::new();
+    if step > 0 {
+        let mut i = start.map_or(0, |s| clamp(s, 0, length));
+        let stop = stop.map_or(length, |s| clamp(s, 0, length));
+        while i < stop {
+            ret.push(i as usize);
+            i = i.saturating_add(step);
+        }
+    }
+    else {
+        let mut i = start.map_or(length - 1, |s| clamp(s, -1, length - 1));
+        let stop = stop.map_or(-1, |s| clamp(s, -1, length - 1));
+        while i > stop {
+            ret.push(i as usize);
+            i = i.saturating_add(step);
+        }
+    }
+
+    ret
+}
+
+// texts inside collections are quoted so ["a, b"] and ["a", "b"] can be told
+// apart when printed
+fn write_nested(f: &mut fmt::Formatter, value: &Value) -> fmt::Result {
+    match value {
+        Value::Text(t) | Value::Safe(t) => write!(f, "{:?}", t),
+        _ => write!(f, "{}", value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Value;
+
+    type Bounds = (Option<i64>, Option<i64>, Option<i64>);
+
+    fn texts(values: &[&str]) -> Value {
+        Value::from(values.to_vec())
+    }
+
+    #[test]
+    fn negative_indexes_count_from_the_end() {
+        let items = texts(&["a", "b", "c"]);
+        assert_eq!(*items.index(&Value::Int(-1)).unwrap(), Value::from("c"));
+        assert_eq!(*items.index(&Value::Int(-3)).unwrap(), Value::from("a"));
+        assert_eq!(items.index(&Value::Int(3)).unwrap_err(), "index 3 out of range for list of length 3");
+        assert_eq!(items.index(&Value::Int(-4)).unwrap_err(), "index -4 out of range for list of length 3");
+        assert_eq!(items.index(&Value::from("0")).unwrap_err(), "list index must be int, not text");
+
+        let name = Value::from("żółw");
+        assert_eq!(*name.index(&Value::Int(1)).unwrap(), Value::from("ó"));
+        assert_eq!(*name.index(&Value::Int(-1)).unwrap(), Value::from("w"));
+        assert_eq!(name.index(&Value::Int(4)).unwrap_err(), "index 4 out of range for text of length 4");
+        assert_eq!(Value::Int(1).index(&Value::Int(0)).unwrap_err(), "int cannot be indexed");
+    }
+
+    #[test]
+    fn slices_follow_python() {
+        let items = texts(&["a", "b", "c", "d", "e"]);
+        let cases: &[(Bounds, &[&str])] = &[
+            ((Some(1), Some(3), None), &["b", "c"]),
+            ((None, Some(2), None), &["a", "b"]),
+            ((Some(-2), None, None), &["d", "e"]),
+            ((None, None, Some(2)), &["a", "c", "e"]),
+            ((None, None, Some(-1)), &["e", "d", "c", "b", "a"]),
+            ((Some(3), Some(0), Some(-2)), &["d", "b"]),
+            ((Some(-100), Some(100), None), &["a", "b", "c", "d", "e"]),
+            ((Some(4), Some(1), None), &[]),
+        ];
+        for &((start, stop, step), expected) in cases {
+            assert_eq!(items.slice(start, stop, step).unwrap(), texts(expected), "{:?}:{:?}:{:?}", start, stop, step);
+        }
+
+        assert_eq!(Value::from("żółwie").slice(None, Some(4), None).unwrap(), Value::from("żółw"));
+        assert_eq!(Value::from("abc").slice(None, None, Some(-1)).unwrap(), Value::from("cba"));
+        assert_eq!(items.slice(None, None, Some(0)).unwrap_err(), "slice step cannot be zero");
+        assert_eq!(Value::Null.slice(None, None, None).unwrap_err(), "null cannot be sliced");
+    }
+}
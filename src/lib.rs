@@ -0,0 +1,8 @@
+pub mod amps;
+
+mod engine;
+
+pub use engine::value::{Value, ValueMap};
+pub use engine::context::Truthiness;
+pub use engine::escape::Escape;
+pub use engine::error::{AmpsError, ErrorKind, Severity, Span};
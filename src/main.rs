@@ -1,8 +1,5 @@
-mod amps;
 mod cli;
 
-mod engine;
-
 fn main() -> Result<(), std::io::Error> {
     cli::main_loop();
 